@@ -1,29 +1,474 @@
+use quote::quote;
+use syn::spanned::Spanned;
 
-use quote::{quote};
-
-#[proc_macro_derive(Node)]
+#[proc_macro_derive(Node, attributes(cod))]
 pub fn node(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = syn::parse_macro_input!(input as syn::DeriveInput);
 
-    match &input.data {
-        syn::Data::Struct(data) => derive_struct(&input, data).into(),
-        _=> unimplemented!(),
+    let result = match &input.data {
+        syn::Data::Struct(data) => derive_struct(&input, data),
+        syn::Data::Enum(data) => derive_enum(&input, data),
+        syn::Data::Union(data) => Err(syn::Error::new(
+            data.union_token.span(), "Cod: `#[derive(Node)]` does not support unions"
+        )),
+    };
+    match result {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
     }
 }
 
-fn derive_struct(derive_input: &syn::DeriveInput, _data: &syn::DataStruct) -> proc_macro2::TokenStream {
+/// The header is the field marked with `#[cod(header)]`, or otherwise the field named
+/// `header`, or otherwise the only field of type `Header`.
+fn derive_struct(derive_input: &syn::DeriveInput, data: &syn::DataStruct) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &derive_input.ident;
+    let shape = Shape::new(quote!(#name), &data.fields, false, &type_params(derive_input))?
+        .ok_or_else(|| missing_header(name.span(), "struct"))?;
+    Ok(implement(derive_input, &[shape]))
+}
+
+/// Each variant needs its own header field, or a single field which itself implements `Node`.
+/// In the latter case, the variant is treated as that node.
+fn derive_enum(derive_input: &syn::DeriveInput, data: &syn::DataEnum) -> syn::Result<proc_macro2::TokenStream> {
     let name = &derive_input.ident;
+    if data.variants.is_empty() {
+        return Err(syn::Error::new(name.span(), "Cod: `#[derive(Node)]` needs at least one enum variant"));
+    }
+    let params = type_params(derive_input);
+    let shapes = data.variants.iter().map(|variant| {
+        let variant_name = &variant.ident;
+        Shape::new(quote!(#name::#variant_name), &variant.fields, true, &params)?
+            .ok_or_else(|| missing_header(variant.span(), "variant"))
+    }).collect::<syn::Result<Vec<_>>>()?;
+    Ok(implement(derive_input, &shapes))
+}
+
+fn type_params(derive_input: &syn::DeriveInput) -> Vec<&syn::Ident> {
+    derive_input.generics.type_params().map(|param| &param.ident).collect()
+}
+
+fn missing_header(span: proc_macro2::Span, kind: &str) -> syn::Error {
+    syn::Error::new(span, format!(
+        "Cod: no header found for this {}. Add a field of type `Header` and mark it with `#[cod(header)]`", kind
+    ))
+}
+
+/// The layout of a struct or one enum variant.
+struct Shape {
+    /// Used in patterns like `#path { .. }`.
+    path: proc_macro2::TokenStream,
+    header: Header,
+    /// Fields that contain `Child`ren. `None` if some field may contain `Child`ren
+    /// in a way that is not understood, in which case Cod falls back to finding the
+    /// children through `Clone`.
+    children: Option<Vec<syn::Member>>,
+    /// Where clause predicates needed for fields that are generic.
+    bounds: Vec<proc_macro2::TokenStream>,
+}
+
+enum Header {
+    Field(syn::Member),
+    /// Delegate to the only field, which implements `Node`.
+    Delegate(syn::Member),
+}
+
+impl Shape {
+    /// `None` if there is no header. `params` are the type parameters of the type.
+    fn new(path: proc_macro2::TokenStream, fields: &syn::Fields, allow_delegate: bool, params: &[&syn::Ident])
+        -> syn::Result<Option<Self>> {
+        let header = match find_header(fields, allow_delegate)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let mut bounds = Vec::new();
+        let children = match &header {
+            Header::Field(_) => child_fields(fields)?.map(|children| {
+                children.into_iter().map(|(member, ty)| {
+                    let node = child_node_type(ty);
+                    if is_param(node, params) {
+                        bounds.push(quote!(#node: cod::NodeClone + Clone));
+                    }
+                    member
+                }).collect()
+            }),
+            Header::Delegate(_) => {
+                let ty = &fields.iter().next().unwrap().ty;
+                if is_param(ty, params) {
+                    bounds.push(quote!(#ty: cod::Node));
+                }
+                Some(Vec::new())
+            },
+        };
+        Ok(Some(Shape { path, header, children, bounds }))
+    }
+
+    /// Pattern binding the given members to the given names.
+    fn pattern(&self, members: &[&syn::Member], names: &[syn::Ident]) -> proc_macro2::TokenStream {
+        let path = &self.path;
+        quote!(#path { #( #members: #names, )* .. })
+    }
+}
+
+fn find_header(fields: &syn::Fields, allow_delegate: bool) -> syn::Result<Option<Header>> {
+    let mut marked = None;
+    for (index, field) in fields.iter().enumerate() {
+        if field_attributes(field)?.header {
+            if marked.is_some() {
+                return Err(syn::Error::new(field.span(), "Cod: more than one field is marked with `#[cod(header)]`"));
+            }
+            marked = Some((index, field));
+        }
+    }
+    let named = || fields.iter().enumerate().find(|(_, field)| {
+        field.ident.as_ref().is_some_and(|ident| ident == "header")
+    });
+    let typed = || {
+        let mut typed = fields.iter().enumerate().filter(|(_, field)| is_header_type(&field.ty));
+        match (typed.next(), typed.next()) {
+            (Some(field), None) => Some(field),
+            _ => None,
+        }
+    };
+    if let Some((index, field)) = marked.or_else(named).or_else(typed) {
+        return Ok(Some(Header::Field(member(index, field))));
+    }
+    if allow_delegate && fields.len() == 1 {
+        let field = fields.iter().next().unwrap();
+        return Ok(Some(Header::Delegate(member(0, field))));
+    }
+    Ok(None)
+}
+
+/// The `cod` attributes of a field.
+#[derive(Default)]
+struct FieldAttributes {
+    /// `#[cod(header)]`: the field is the header.
+    header: bool,
+    /// `#[cod(skip)]`: the field contains no `Child`ren, even though its type is not known to Cod.
+    skip: bool,
+}
+
+/// Other `cod` attributes than `#[cod(header)]` and `#[cod(skip)]` are errors.
+fn field_attributes(field: &syn::Field) -> syn::Result<FieldAttributes> {
+    const EXPECTED: &str = "Cod: expected `#[cod(header)]` or `#[cod(skip)]`";
+    let mut attributes = FieldAttributes::default();
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("cod")) {
+        match attr.parse_meta()? {
+            syn::Meta::List(list) if list.nested.len() == 1 => match list.nested.first() {
+                Some(syn::NestedMeta::Meta(syn::Meta::Path(path))) if path.is_ident("header") => {
+                    attributes.header = true;
+                },
+                Some(syn::NestedMeta::Meta(syn::Meta::Path(path))) if path.is_ident("skip") => {
+                    attributes.skip = true;
+                },
+                _ => return Err(syn::Error::new(list.span(), EXPECTED)),
+            },
+            meta => return Err(syn::Error::new(meta.span(), EXPECTED)),
+        }
+    }
+    Ok(attributes)
+}
+
+fn member(index: usize, field: &syn::Field) -> syn::Member {
+    match &field.ident {
+        Some(ident) => syn::Member::Named(ident.clone()),
+        None => syn::Member::Unnamed(syn::Index::from(index)),
+    }
+}
+
+fn implement(derive_input: &syn::DeriveInput, shapes: &[Shape]) -> proc_macro2::TokenStream {
+    let name = &derive_input.ident;
+    let mut generics = derive_input.generics.clone();
+    {
+        let where_clause = generics.make_where_clause();
+        for param in derive_input.generics.type_params() {
+            let ident = &param.ident;
+            where_clause.predicates.push(syn::parse_quote!(#ident: 'static));
+        }
+        for bound in shapes.iter().flat_map(|shape| &shape.bounds) {
+            where_clause.predicates.push(syn::parse_quote!(#bound));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let header_arms = shapes.iter().map(|shape| {
+        let binding = binding("header", 0);
+        match &shape.header {
+            Header::Field(field) => {
+                let pattern = shape.pattern(&[field], std::slice::from_ref(&binding));
+                (quote!(#pattern => #binding), quote!(#pattern => #binding))
+            },
+            Header::Delegate(field) => {
+                let pattern = shape.pattern(&[field], std::slice::from_ref(&binding));
+                (
+                    quote!(#pattern => cod::Node::header(#binding)),
+                    quote!(#pattern => cod::Node::header_mut(#binding)),
+                )
+            },
+        }
+    }).collect::<Vec<_>>();
+    let header = header_arms.iter().map(|(header, _)| header);
+    let header_mut = header_arms.iter().map(|(_, header_mut)| header_mut);
+
+    let poll_all = poll_arms(shapes, |bindings| quote! {
+        #( cod::ChildContainer::poll_all(#bindings); )*
+    }, |inner| quote!(cod::Node::poll_all(#inner)));
+    let poll_all_mut = poll_arms(shapes, |bindings| quote! {
+        #( cod::ChildContainer::poll_all_mut(#bindings); )*
+    }, |inner| quote!(cod::Node::poll_all_mut(#inner)));
+    // cheap lookups first, so that large containers are not scanned in vain
+    let poll_child_mut = poll_arms(shapes, |bindings| quote! {
+        #( if cod::ChildContainer::poll_child_mut_hinted(&mut *#bindings, _id) { return } )*
+        #( if cod::ChildContainer::poll_child_mut(#bindings, _id) { return } )*
+    }, |inner| quote!(cod::Node::poll_child_mut(#inner, _id)));
+    let for_each_child = poll_arms(shapes, |bindings| quote! {
+        #( cod::ChildContainer::for_each_child(#bindings, _f); )*
+    }, |inner| quote!(cod::Node::for_each_child(#inner, _f)));
+    let take_child = poll_arms(shapes, |bindings| quote! {
+        #( if let Some(node) = cod::ChildContainer::take_child(#bindings, _id) { return Some(node) } )*
+    }, |inner| quote!(return cod::Node::take_child(#inner, _id)));
+    let implements_poll_all = implements_arms(shapes, quote!(implements_poll_all));
+    let implements_poll_child = implements_arms(shapes, quote!(implements_poll_child));
 
     quote! {
-        impl cod::Node for #name {
-            
+        impl #impl_generics cod::Node for #name #ty_generics #where_clause {
+
             fn header(&self) -> &cod::Header {
-                &self.header
+                match self {
+                    #( #header, )*
+                }
             }
 
             fn header_mut(&mut self) -> &mut cod::Header {
-                &mut self.header
+                match self {
+                    #( #header_mut, )*
+                }
+            }
+
+            fn poll_all(&self) {
+                match self {
+                    #( #poll_all )*
+                }
+            }
+
+            fn poll_all_mut(&mut self) {
+                match self {
+                    #( #poll_all_mut )*
+                }
+            }
+
+            fn implements_poll_all(&self) -> bool {
+                match self {
+                    #( #implements_poll_all )*
+                }
+            }
+
+            fn poll_child_mut(&mut self, _id: cod::ID) {
+                match self {
+                    #( #poll_child_mut )*
+                }
+            }
+
+            fn implements_poll_child(&self) -> bool {
+                match self {
+                    #( #implements_poll_child )*
+                }
+            }
+
+            fn for_each_child(&self, _f: &mut dyn FnMut(&dyn cod::NodeClone)) {
+                match self {
+                    #( #for_each_child )*
+                }
+            }
+
+            fn take_child(&mut self, _id: cod::ID) -> Option<cod::Rc<dyn cod::NodeClone>> {
+                match self {
+                    #( #take_child )*
+                }
+                None
+            }
+        }
+    }
+}
+
+fn binding(prefix: &str, index: usize) -> syn::Ident {
+    syn::Ident::new(&format!("__cod_{}_{}", prefix, index), proc_macro2::Span::call_site())
+}
+
+/// Match arms calling `fields` with bindings to all child containers,
+/// or `delegate` with a binding to the inner node.
+fn poll_arms(
+    shapes: &[Shape],
+    fields: impl Fn(&[syn::Ident]) -> proc_macro2::TokenStream,
+    delegate: impl Fn(&syn::Ident) -> proc_macro2::TokenStream,
+) -> Vec<proc_macro2::TokenStream> {
+    shapes.iter().map(|shape| {
+        match (&shape.header, &shape.children) {
+            (Header::Delegate(field), _) => {
+                let binding = binding("inner", 0);
+                let pattern = shape.pattern(&[field], std::slice::from_ref(&binding));
+                let body = delegate(&binding);
+                quote!(#pattern => #body,)
+            },
+            (Header::Field(_), Some(children)) => {
+                let members = children.iter().collect::<Vec<_>>();
+                let bindings = (0..children.len()).map(|index| binding("child", index)).collect::<Vec<_>>();
+                let pattern = shape.pattern(&members, &bindings);
+                let body = fields(&bindings);
+                quote!(#pattern => { #body })
+            },
+            (Header::Field(_), None) => {
+                let pattern = shape.pattern(&[], &[]);
+                quote!(#pattern => {})
+            },
+        }
+    }).collect()
+}
+
+/// Match arms for `implements_poll_all` or `implements_poll_child`, given as `method`.
+fn implements_arms(shapes: &[Shape], method: proc_macro2::TokenStream) -> Vec<proc_macro2::TokenStream> {
+    shapes.iter().map(|shape| {
+        match (&shape.header, &shape.children) {
+            (Header::Delegate(field), _) => {
+                let binding = binding("inner", 0);
+                let pattern = shape.pattern(&[field], std::slice::from_ref(&binding));
+                quote!(#pattern => cod::Node::#method(#binding),)
+            },
+            (Header::Field(_), children) => {
+                let pattern = shape.pattern(&[], &[]);
+                let implements = children.is_some();
+                quote!(#pattern => #implements,)
+            },
+        }
+    }).collect()
+}
+
+/// Members and types of the fields that contain `Child`ren, see [`Shape::children`].
+/// `None` if the type of another field is not known to be free of `Child`ren, and it
+/// is not marked with `#[cod(skip)]`.
+fn child_fields(fields: &syn::Fields) -> syn::Result<Option<Vec<(syn::Member, &syn::Type)>>> {
+    let mut result = Vec::new();
+    let mut understood = true;
+    for (index, field) in fields.iter().enumerate() {
+        let skip = field_attributes(field)?.skip;
+        if is_child_container(&field.ty) {
+            if skip {
+                return Err(syn::Error::new(field.span(), "Cod: `#[cod(skip)]` is for fields without `Child`ren"));
             }
+            result.push((member(index, field), &field.ty));
+        } else if !skip && !is_leaf_type(&field.ty) {
+            // e.g. behind a type alias or inside another struct
+            understood = false;
         }
     }
-}
\ No newline at end of file
+    Ok(understood.then_some(result))
+}
+
+fn last_segment(ty: &syn::Type) -> Option<&syn::PathSegment> {
+    match ty {
+        syn::Type::Path(syn::TypePath { qself: None, path }) => path.segments.last(),
+        _ => None,
+    }
+}
+
+fn is_header_type(ty: &syn::Type) -> bool {
+    last_segment(ty).is_some_and(|segment| segment.ident == "Header")
+}
+
+/// Whether `ty` is one of the type parameters.
+fn is_param(ty: &syn::Type, params: &[&syn::Ident]) -> bool {
+    match ty {
+        syn::Type::Path(syn::TypePath { qself: None, path }) => {
+            params.iter().any(|param| path.is_ident(*param))
+        },
+        _ => false,
+    }
+}
+
+/// The type of node that a child container contains, `T` in `Vec<Child<T>>`.
+/// Only call this if `is_child_container(ty)`.
+fn child_node_type(ty: &syn::Type) -> &syn::Type {
+    let segment = last_segment(ty).unwrap();
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(arguments) => match arguments.args.first() {
+            Some(syn::GenericArgument::Type(inner)) => {
+                if segment.ident == "Child" {
+                    inner
+                } else {
+                    child_node_type(inner)
+                }
+            },
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// `Child<T>`, or `Option`, `Vec` or `im::Vector` of a child container.
+fn is_child_container(ty: &syn::Type) -> bool {
+    let segment = match last_segment(ty) {
+        Some(segment) => segment,
+        None => return false,
+    };
+    match segment.ident.to_string().as_str() {
+        "Child" => true,
+        "Option" | "Vec" | "Vector" => match &segment.arguments {
+            syn::PathArguments::AngleBracketed(arguments) => {
+                match arguments.args.first() {
+                    Some(syn::GenericArgument::Type(inner)) if arguments.args.len() == 1 => {
+                        is_child_container(inner)
+                    },
+                    _ => false,
+                }
+            },
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Types without generic arguments that contain no `Child`ren, and `NodeRef<T>`.
+const LEAF_TYPES: &[&str] = &[
+    "bool", "char", "str", "String",
+    "i8", "i16", "i32", "i64", "i128", "isize",
+    "u8", "u16", "u32", "u64", "u128", "usize",
+    "f32", "f64",
+    "Header", "NodeRef", "ID",
+];
+
+/// Containers that contain no `Child`ren if their type arguments do not.
+const LEAF_CONTAINERS: &[&str] = &[
+    "Option", "Box", "Rc", "Arc", "Vec", "VecDeque", "Vector",
+    "HashMap", "HashSet", "BTreeMap", "BTreeSet", "OrdMap", "OrdSet",
+];
+
+/// Whether `ty` is known to contain no `Child`ren: one of the [`LEAF_TYPES`], or
+/// references, tuples, arrays and [`LEAF_CONTAINERS`] of those. Anything else, such as
+/// a type alias, may hide `Child`ren.
+fn is_leaf_type(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(syn::TypePath { qself: None, path }) => {
+            let segment = path.segments.last().unwrap();
+            let name = segment.ident.to_string();
+            if LEAF_TYPES.contains(&name.as_str()) {
+                return true;
+            }
+            if !LEAF_CONTAINERS.contains(&name.as_str()) {
+                return false;
+            }
+            match &segment.arguments {
+                syn::PathArguments::AngleBracketed(arguments) => arguments.args.iter().all(|argument| match argument {
+                    syn::GenericArgument::Type(inner) => is_leaf_type(inner),
+                    syn::GenericArgument::Lifetime(_) => true,
+                    _ => false,
+                }),
+                _ => false,
+            }
+        },
+        syn::Type::Reference(reference) => is_leaf_type(&reference.elem),
+        syn::Type::Tuple(tuple) => tuple.elems.iter().all(is_leaf_type),
+        syn::Type::Array(array) => is_leaf_type(&array.elem),
+        syn::Type::Slice(slice) => is_leaf_type(&slice.elem),
+        syn::Type::Paren(paren) => is_leaf_type(&paren.elem),
+        _ => false,
+    }
+}
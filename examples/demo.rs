@@ -3,6 +3,7 @@ use std::vec::Vec;
 use cod::Node;
 
 #[derive(Node, Clone, Debug)]
+#[allow(dead_code)]
 struct A {
     header: cod::Header,
     some_data: i32,
@@ -19,13 +20,13 @@ struct B {
 
 fn main() {
     let state1 = cod::State::construct(|| {
-        let header = cod::Header::new();
         A {
+            header: Default::default(),
             some_data: 15,
-            child: cod::Child::with_parent(&header, B { header: Default::default(), data: [1, 2, 3].to_vec() }),
+            // the parent ID is filled in once the state is constructed
+            child: cod::Child::new(B { header: Default::default(), data: [1, 2, 3].to_vec() }),
             optional_child: None,
             child_list: vec![],
-            header,
         }
     });
     println!("Initial state:");
@@ -0,0 +1,168 @@
+//! Keeping track of the `NodeRef`s between nodes, and applying their [`RefPolicy`]
+//! when their target is removed.
+
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+use crate::{NodeClone, State, ID, Rc, Weak, CodError, RefPolicy, Indexes, apply_updates, im};
+use crate::context::{with_context, Context, Session, PollReason};
+
+/// The `NodeRef`s between the nodes of a state, in both directions. Kept in sync with
+/// the ID lookup by [`apply_updates`].
+#[derive(Clone, Default)]
+pub(crate) struct Backlinks {
+    /// IDs of the nodes referring to a node, by its ID
+    referrers: im::HashMap<ID, im::OrdSet<ID>>,
+    /// IDs of the nodes a node refers to, by its ID
+    targets: im::HashMap<ID, im::OrdSet<ID>>,
+    /// Nodes removed while referred to, whose referrers are yet to be dealt with
+    dangling: Vec<ID>,
+}
+
+impl Backlinks {
+    /// Record the references in the current version of `node`.
+    pub(crate) fn update(&mut self, id: ID, node: &dyn NodeClone) {
+        let targets: im::OrdSet<ID> = with_context(|c| Context::refs(c, node)).into_iter()
+            .map(|(target, _)| target)
+            .collect();
+        self.unlink(id);
+        for &target in &targets {
+            self.referrers.entry(target).or_default().insert(id);
+        }
+        if !targets.is_empty() {
+            self.targets.insert(id, targets);
+        }
+    }
+
+    /// Forget the references of a removed node, and remember it if it is referred to.
+    pub(crate) fn remove(&mut self, id: ID) {
+        self.unlink(id);
+        if self.referrers.contains_key(&id) {
+            self.dangling.push(id);
+        }
+    }
+
+    fn unlink(&mut self, referrer: ID) {
+        for target in self.targets.remove(&referrer).into_iter().flatten() {
+            if let Some(referrers) = self.referrers.get_mut(&target) {
+                referrers.remove(&referrer);
+                if referrers.is_empty() {
+                    self.referrers.remove(&target);
+                }
+            }
+        }
+    }
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// Keep track of the [`NodeRef`](crate::NodeRef)s between nodes, so that
+    /// [`referrers`](State::referrers) does not need to look through all nodes, and the
+    /// [`RefPolicy`] of a reference is applied when its target is removed.
+    /// The index is kept up to date in the states derived from this one.
+    pub fn with_backlinks(mut self) -> Self {
+        let mut backlinks = Backlinks::default();
+        for (&id, node) in &self.id_lookup {
+            if let Some(node) = Weak::upgrade(node) {
+                backlinks.update(id, &*node);
+            }
+        }
+        self.indexes.backlinks = Some(backlinks);
+        self
+    }
+
+    /// Whether this state keeps track of references, see [`with_backlinks`](State::with_backlinks).
+    pub fn has_backlinks(&self) -> bool {
+        self.indexes.backlinks.is_some()
+    }
+
+    /// The IDs of the nodes with a `NodeRef` to the node with the given ID, in order.
+    pub fn referrers(&self, id: ID) -> Vec<ID> {
+        match &self.indexes.backlinks {
+            Some(backlinks) => backlinks.referrers.get(&id).into_iter().flatten().copied().collect(),
+            None => {
+                let mut referrers: Vec<ID> = self.id_lookup.iter()
+                    .filter(|(_, node)| Weak::upgrade(node).is_some_and(|node| {
+                        with_context(|c| Context::refs(c, &*node)).iter().any(|&(target, _)| target == id)
+                    }))
+                    .map(|(&referrer, _)| referrer)
+                    .collect();
+                referrers.sort_unstable();
+                referrers
+            },
+        }
+    }
+}
+
+/// Apply the [`RefPolicy`] of the references to nodes removed in a commit. `id_lookup`
+/// and `indexes` must include the updates of the mutation session, and `edited` holds the
+/// new versions of the edited nodes. Referrers that are nullified or lose a child are
+/// added to `edited`.
+pub(crate) fn settle_refs(
+    id_lookup: &mut im::HashMap<ID, Weak<dyn NodeClone>>,
+    indexes: &mut Indexes,
+    edited: &mut HashMap<ID, Rc<dyn NodeClone>>,
+) -> Result<(), CodError> {
+    let Some(backlinks) = &mut indexes.backlinks else { return Ok(()) };
+    for (&id, node) in edited.iter() {
+        if id_lookup.contains_key(&id) {
+            backlinks.update(id, &**node);
+        }
+    }
+    while let Some(target) = indexes.backlinks.as_mut().unwrap().dangling.pop() {
+        // moved rather than removed
+        if id_lookup.contains_key(&target) { continue }
+        let referrers = indexes.backlinks.as_ref().unwrap().referrers.get(&target).cloned().unwrap_or_default();
+        for referrer in referrers {
+            // removed along with the target, or by an earlier cascade
+            if !id_lookup.contains_key(&referrer) { continue }
+            let node = match edited.get(&referrer) {
+                Some(node) => Rc::clone(node),
+                None => Weak::upgrade(&id_lookup[&referrer]).unwrap(),
+            };
+            let policies: Vec<RefPolicy> = with_context(|c| Context::refs(c, &*node)).into_iter()
+                .filter(|&(id, _)| id == target)
+                .map(|(_, policy)| policy)
+                .collect();
+            if policies.contains(&RefPolicy::Reject) {
+                return Err(CodError::StillReferenced { id: target, referrer })
+            } else if policies.contains(&RefPolicy::Cascade) {
+                cascade(id_lookup, indexes, edited, &*node)?;
+            } else if !policies.is_empty() {
+                let nullified = with_context(|c| Context::nullifying_refs(c, HashSet::from([target]), || {
+                    Context::suspend(c, || node.dyn_clone())
+                }));
+                indexes.backlinks.as_mut().unwrap().update(referrer, &*nullified);
+                edited.insert(referrer, nullified);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Remove `node` from its parent, as if its `Child` had been dropped in a mutation session.
+fn cascade(
+    id_lookup: &mut im::HashMap<ID, Weak<dyn NodeClone>>,
+    indexes: &mut Indexes,
+    edited: &mut HashMap<ID, Rc<dyn NodeClone>>,
+    node: &dyn NodeClone,
+) -> Result<(), CodError> {
+    let id = node.header().id;
+    let parent_id = node.header().parent_id().ok_or(CodError::CannotRemove(id))?;
+    let parent = match edited.entry(parent_id) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let current = id_lookup.get(&parent_id).and_then(Weak::upgrade)
+                .ok_or(CodError::ChildNotFound { parent: parent_id })?;
+            entry.insert(current)
+        }
+    };
+    if Rc::get_mut(parent).is_none() {
+        // the children are shared, not deep copied
+        *parent = with_context(|c| Context::suspend(c, || parent.dyn_clone()));
+    }
+    let parent = Rc::get_mut(parent).unwrap();
+    let session = Session::begin();
+    let taken = with_context(|c| Context::suspend(c, || parent.take_child(id)))
+        .ok_or(CodError::CannotRemove(id))?;
+    with_context(|c| Context::poll_dyn(c, PollReason::Drop, taken));
+    apply_updates(id_lookup, indexes, session.end()?);
+    Ok(())
+}
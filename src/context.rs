@@ -1,58 +1,105 @@
 //! Context defines thread-local context in order to affect what happens in Clone
 //! and Drop, among other operations involving tree mutation.
+//!
+//! Each mutation session has a context of its own, see [`Session`].
 
+use std::any::{Any, TypeId};
 use std::cell::RefCell;
-use crate::{NodeClone, ID, Rc, Weak};
+use std::collections::{HashMap, HashSet};
+use crate::{NodeClone, ID, Rc, Weak, CodError, RefPolicy};
 use crate::danger_zone::downcast_rc;
 use crate::id::new_id;
 
 thread_local! {
-    pub(crate) static CONTEXT: RefCell<Context> = Default::default();
+    /// Contexts of the live sessions on this thread. The last one is current.
+    static SESSIONS: RefCell<Vec<std::rc::Rc<RefCell<Context>>>> = Default::default();
+    /// Current when there are no live sessions.
+    static IDLE: std::rc::Rc<RefCell<Context>> = Default::default();
 }
 
-#[derive(Default)]
-pub(crate) struct Context {
-    status: ContextStatus,
-    updates: Vec<IDMapUpdate>,
-    /// FIXME: is never shrinked
-    deep_copy_id_stack: Vec<ID>,
+/// Run `f` with the context of the current session.
+pub(crate) fn with_context<O>(f: impl FnOnce(&RefCell<Context>) -> O) -> O {
+    let context = SESSIONS.with(|sessions| sessions.borrow().last().cloned())
+        .unwrap_or_else(|| IDLE.with(std::rc::Rc::clone));
+    f(&context)
 }
 
-#[derive(Clone)]
-enum ContextStatus {
-    Inactive,
-    Mutation(TraversalStatus),
-    Propagation(Replacement, bool)
+thread_local! {
+    /// Nodes waiting to be freed by the outermost [`drop_iteratively`], if one is in progress.
+    static DROP_QUEUE: RefCell<Option<Vec<Rc<dyn NodeClone>>>> = const { RefCell::new(None) };
 }
 
-impl Default for ContextStatus {
-    fn default() -> ContextStatus {
-        ContextStatus::Inactive
+/// Drop `node` and the subtree freed along with it one node at a time, rather than
+/// recursing on the execution stack for each level of the tree.
+///
+/// The `Child`ren freed here are not polled. When the outermost `Child` was dropped,
+/// it already got polled for its whole subtree.
+pub(crate) fn drop_iteratively(node: Rc<dyn NodeClone>) {
+    let outermost = DROP_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        match queue.as_mut() {
+            Some(queue) => {
+                // freed by the outermost call, once the caller has returned
+                queue.push(node);
+                None
+            },
+            None => {
+                *queue = Some(Vec::new());
+                Some(node)
+            },
+        }
+    });
+    if let Some(node) = outermost {
+        with_context(|c| Context::suspend(c, || {
+            drop(node);
+            while let Some(node) = DROP_QUEUE.with(|queue| queue.borrow_mut().as_mut().unwrap().pop()) {
+                drop(node);
+            }
+        }));
+        DROP_QUEUE.with(|queue| *queue.borrow_mut() = None);
     }
 }
 
-/// Traversal occurs when a `Child` is dropped or cloned (meaning a deep copy should be made).
-/// Both require recursively traversing the whole subtree of that `Child`.
-/// Cod has exclusive control of the traversal, and only one can be in progress at a time.
-///
-/// Implemented as a DFS on the execution stack (there's not much other options because
-/// we cannot go back to update a `Child` pointer after it has been polled through clone).
-/// This is fine, since `Drop` on complete deallocation would do that anyways.
-#[derive(Clone, Copy)]
-enum TraversalStatus {
-    Inactive,
-    DeepCopy,
-    Removal,
+#[derive(Default)]
+pub(crate) struct Context {
+    status: ContextStatus,
+    updates: Vec<IDMapUpdate>,
+    /// IDs of the nodes created or copied during the mutation session
+    copied: HashSet<ID>,
+    /// Children found during [`ContextStatus::Enumeration`]
+    enumerated: Vec<Rc<dyn NodeClone>>,
+    /// Targets of the `NodeRef`s cloned during [`Context::refs`]
+    enumerated_refs: Option<Vec<(ID, RefPolicy)>>,
+    /// `Child`ren to replace during [`ContextStatus::Propagation`], by ID
+    replacements: HashMap<ID, Rc<dyn NodeClone>>,
+    /// IDs already replaced during the current propagation step
+    replaced: HashSet<ID>,
+    /// The first error encountered in the current mutation session or propagation step.
+    /// Errors are raised from `Clone` and `Drop`, so they cannot be returned directly.
+    error: Option<CodError>,
+    /// Whether deep copies remap `NodeRef`s, see [`Context::remapping_refs`]
+    remap_refs: bool,
+    /// New IDs by original ID of the nodes being deep copied, if `NodeRef`s are remapped
+    new_ids: HashMap<ID, ID>,
+    /// Targets of the `NodeRef`s to nullify when cloned, see [`Context::nullifying_refs`]
+    nullified: HashSet<ID>,
 }
 
-#[derive(Clone)]
-pub(crate) struct Replacement {
-    pub(crate) id: ID,
-    pub(crate) replace_with: Rc<dyn NodeClone>,
+#[derive(Clone, Default)]
+enum ContextStatus {
+    #[default]
+    Inactive,
+    /// A mutation session is in progress. Cloning a `Child` makes a deep copy, and
+    /// dropping one removes its subtree, see [`Context::deep_copy`] and [`Context::remove`].
+    Mutation,
+    /// Replacing some `Child`ren of a node with new versions, see [`Context::set_replacements`]
+    Propagation,
+    /// Collecting the direct children of a node, see [`Context::children`]
+    Enumeration,
 }
 
 pub(crate) enum IDMapUpdate {
-    Set(ID, Weak<dyn NodeClone>),
+    Set(ID, Weak<dyn NodeClone>, TypeId),
     Erase(ID),
 }
 
@@ -67,6 +114,8 @@ pub(crate) enum PollReason {
     MakeMutPost,
     Manual,
     ManualMut,
+    /// Setting the parent ID of the topmost node, if given
+    Relink(Option<ID>),
 }
 
 impl Context {
@@ -88,198 +137,406 @@ impl Context {
         let status = context.borrow().status.clone();
         match status {
             ContextStatus::Inactive => None,
-            ContextStatus::Mutation(traversal) => {
-                match traversal {
-                    TraversalStatus::Inactive => {
-                        match reason {
-                            PollReason::Construct => {
-                                // register new node
-                                context.borrow_mut().node_map_update(id, &node);
-                                None
-                            },
-                            PollReason::DeepCopy(parent_id) => {
-                                // initiate deep copy changing parent
-                                {
-                                    let mut context = context.borrow_mut();
-                                    context.status = ContextStatus::Mutation(TraversalStatus::DeepCopy);
-                                    context.deep_copy_id_stack.clear();
-                                    context.deep_copy_id_stack.push(parent_id);
-                                }
-                                let new_node = Context::poll_dyn(context, reason, node);
-                                context.borrow_mut().status = ContextStatus::Mutation(TraversalStatus::Inactive);
-                                new_node
-                            },
-                            PollReason::Clone => {
-                                // initiate deep copy without changing parent
-                                {
-                                    let mut context = context.borrow_mut();
-                                    context.status = ContextStatus::Mutation(TraversalStatus::DeepCopy);
-                                    context.deep_copy_id_stack.clear();
-                                }
-                                let new_node = Context::poll_dyn(context, reason, node);
-                                context.borrow_mut().status = ContextStatus::Mutation(TraversalStatus::Inactive);
-                                new_node
-                            },
-                            PollReason::Drop => {
-                                // initiate recursive removal
-                                context.borrow_mut().status = ContextStatus::Mutation(TraversalStatus::Removal);
-                                let new_node = Context::poll_dyn(context, reason, node);
-                                context.borrow_mut().status = ContextStatus::Mutation(TraversalStatus::Inactive);
-                                new_node
-                            },
-                            PollReason::MakeMutPre => {
-                                // temporarily deactivate the context
-                                context.borrow_mut().status = ContextStatus::Inactive;
-                                // clone the node (clones will now not cause polls)
-                                let new_node = node.dyn_clone();
-                                // reactivate context
-                                context.borrow_mut().status = ContextStatus::Mutation(TraversalStatus::Inactive);
-                                // do not store map update yet. MakeMutPost handles that.
-                                Some(new_node)
-                            },
-                            PollReason::MakeMutPost => {
-                                context.borrow_mut().node_map_update(id, &node);
-                                None
-                            }
-                            _ => panic!("Cod: `poll()` or `poll_mut()` called in an unexpected context")
-                        }
+            ContextStatus::Mutation => {
+                match reason {
+                    PollReason::Construct => {
+                        // register new node
+                        context.borrow_mut().node_map_update(id, &node);
+                        None
                     },
-                    TraversalStatus::DeepCopy => {
-                        match reason {
-                            PollReason::Clone | PollReason::ManualMut => {
-                                let mut new_node;
-                                let cloned_id = new_id();
-                                context.borrow_mut().deep_copy_id_stack.push(cloned_id);
-                                if node.implements_poll_all() {
-                                    // temporarily deactivate the context
-                                    context.borrow_mut().status = ContextStatus::Inactive;
-                                    // clone the node (clones will now not cause polls)
-                                    new_node = node.dyn_clone();
-                                    // reactivate context
-                                    context.borrow_mut().status = ContextStatus::Mutation(TraversalStatus::DeepCopy);
-                                    // recurse. inner_ref is now unique so does not panic
-                                    Rc::get_mut(&mut new_node).unwrap().poll_all_mut();
-                                } else {
-                                    // recurse directly, clones will cause polls
-                                    new_node = node.dyn_clone();
-                                }
-                                context.borrow_mut().deep_copy_id_stack.pop();
-                                let mut header = Rc::get_mut(&mut new_node).unwrap().header_mut();
-                                header.id = cloned_id;
-                                // parent_id not changed on the topmost node of the deep copy
-                                if let Some(parent_id) = context.borrow_mut().deep_copy_id_stack.last() {
-                                    header.parent_id = Some(*parent_id);
-                                }
-                                // store map update
-                                context.borrow_mut().node_map_update(cloned_id, &new_node);
-                                Some(new_node)
-                            },
-                            PollReason::Manual => {
-                                panic!("Cod: `poll()` called when `poll_mut()` was expected")
-                            },
-                            _ => panic!()
-                        }
+                    // deep copy changing parent
+                    PollReason::DeepCopy(parent_id) => Some(Context::deep_copy(context, node, Some(parent_id), true)),
+                    // deep copy without changing parent
+                    PollReason::Clone => Some(Context::deep_copy(context, node, None, true)),
+                    // copy keeping IDs, rewriting parent IDs
+                    PollReason::Relink(parent_id) => Some(Context::deep_copy(context, node, parent_id, false)),
+                    PollReason::Drop => {
+                        Context::remove(context, node);
+                        None
+                    },
+                    PollReason::MakeMutPre => {
+                        // clone the node without polling its children
+                        let new_node = Context::suspend(context, || node.dyn_clone());
+                        // do not store map update yet. MakeMutPost handles that.
+                        Some(new_node)
+                    },
+                    PollReason::MakeMutPost => {
+                        context.borrow_mut().node_map_update(id, &node);
+                        None
                     },
-                    TraversalStatus::Removal => {
+                    PollReason::Manual | PollReason::ManualMut => {
+                        context.borrow_mut().fail(CodError::UnexpectedPoll(id))
+                    },
+                }
+            },
+            ContextStatus::Propagation => {
+                let replace_with = context.borrow().replacements.get(&id).cloned();
+                match replace_with {
+                    Some(replace_with) => {
                         match reason {
-                            PollReason::Clone | PollReason::Manual => {
-                                if node.implements_poll_all() {
-                                    node.poll_all();
-                                } else {
-                                    // namesake of this crate: Clone on Drop
-                                    // let me explain.
-                                    // we are cleaning up the node pointed to by `child`, but in order
-                                    // to do that, we need to iterate over its subtree.
-                                    // we abuse Clone as a form of reflection to find the children
-                                    // of this node.
-                                    node.cod();
+                            PollReason::Clone | PollReason::ManualMut => {
+                                let mut context = context.borrow_mut();
+                                if !context.replaced.insert(id) {
+                                    return context.fail(CodError::DuplicateID(id))
                                 }
-                                // store map removal
-                                context.borrow_mut().node_map_erase(id);
-                                None
+                                Some(replace_with)
                             },
-                            PollReason::Drop => None,
-                            _ => panic!()
+                            PollReason::Manual => context.borrow_mut().fail(CodError::PollNotMut(id)),
+                            _ => context.borrow_mut().fail(CodError::UnexpectedPoll(id)),
                         }
                     },
+                    // no match here
+                    None => None,
                 }
             },
-            ContextStatus::Propagation(Replacement { id: target_id, replace_with }, found) => {
-                // TODO: early exit if already found? feels dubious
-                if id == target_id {
-                    match reason {
-                        PollReason::Clone | PollReason::ManualMut => {
-                            if found {
-                                panic!("Cod: The same ID was found in multiple `Child`s, state is corrupted")
-                            }
-                            match &mut context.borrow_mut().status {
-                                ContextStatus::Propagation(_, ref mut found) => {
-                                    *found = true;
-                                },
-                                _ => ()
-                            }
-                            Some(replace_with.clone())
-                        },
-                        PollReason::Manual => {
-                            panic!("Cod: `poll()` called when `poll_mut()` was expected")
-                        }
-                        _ => panic!()
-                    }
-                } else {
-                    // no match here
-                    None
+            ContextStatus::Enumeration => {
+                match reason {
+                    PollReason::Clone | PollReason::Manual | PollReason::ManualMut => {
+                        context.borrow_mut().enumerated.push(node);
+                        None
+                    },
+                    // only reading here, so other polls do no harm
+                    _ => None,
                 }
             },
         }
     }
 
+    /// Copy the subtree of `node`, registering the copies. With `new_ids: false`, the IDs
+    /// are kept and only the parent IDs are rewritten, which links up freshly loaded trees.
+    /// The parent ID of the topmost copy is set to `parent_id` if given.
+    ///
+    /// The copies are made bottom up, each one pointing to the copies of its children.
+    /// Pending nodes are kept on the heap, so deep trees do not overflow the execution stack.
+    fn deep_copy(context: &RefCell<Self>, node: Rc<dyn NodeClone>, parent_id: Option<ID>, new_ids: bool)
+        -> Rc<dyn NodeClone> {
+        enum Visit {
+            Enter(Rc<dyn NodeClone>, Option<ID>),
+            /// The children, listed by their original IDs, have been copied
+            Leave(Rc<dyn NodeClone>, Option<ID>, ID, Vec<ID>),
+        }
+        let top_id = node.header().id;
+        let remap = new_ids && context.borrow().remap_refs;
+        let mut id_map = HashMap::new();
+        if remap {
+            // references may point to nodes copied later on, so all new IDs are chosen first
+            let mut stack = vec![Rc::clone(&node)];
+            while let Some(node) = stack.pop() {
+                id_map.insert(node.header().id, new_id());
+                stack.extend(Context::children(context, &*node));
+            }
+        }
+        let prev_ids = std::mem::replace(&mut context.borrow_mut().new_ids, id_map);
+        // copies by original ID, waiting to be put into their parent
+        let mut copies: HashMap<ID, Rc<dyn NodeClone>> = HashMap::new();
+        let mut stack = vec![Visit::Enter(node, parent_id)];
+        while let Some(visit) = stack.pop() {
+            match visit {
+                Visit::Enter(node, parent_id) => {
+                    let id = node.header().id;
+                    let cloned_id = if remap {
+                        context.borrow().new_ids[&id]
+                    } else if new_ids {
+                        new_id()
+                    } else {
+                        id
+                    };
+                    let children = Context::children(context, &*node);
+                    let child_ids = children.iter().map(|child| child.header().id).collect();
+                    stack.push(Visit::Leave(node, parent_id, cloned_id, child_ids));
+                    stack.extend(children.into_iter().rev().map(|child| Visit::Enter(child, Some(cloned_id))));
+                },
+                Visit::Leave(node, parent_id, cloned_id, child_ids) => {
+                    let replacements = child_ids.into_iter()
+                        .filter_map(|id| Some((id, copies.remove(&id)?)))
+                        .collect();
+                    let mut new_node = Context::copy_replacing(context, &*node, replacements);
+                    let header = Rc::get_mut(&mut new_node).unwrap().header_mut();
+                    header.id = cloned_id;
+                    if parent_id.is_some() {
+                        header.set_parent_id(parent_id);
+                    }
+                    // store map update
+                    context.borrow_mut().node_map_update(cloned_id, &new_node);
+                    copies.insert(node.header().id, new_node);
+                },
+            }
+        }
+        context.borrow_mut().new_ids = prev_ids;
+        copies.remove(&top_id).unwrap()
+    }
+
+    /// Run `f`, with the deep copies made in it remapping `NodeRef`s that point inside
+    /// the copied subtree to the copies.
+    pub(crate) fn remapping_refs<O>(context: &RefCell<Self>, f: impl FnOnce() -> O) -> O {
+        let prev = std::mem::replace(&mut context.borrow_mut().remap_refs, true);
+        let result = f();
+        context.borrow_mut().remap_refs = prev;
+        result
+    }
+
+    /// Run `f`, with the `NodeRef`s to the given targets becoming null when cloned.
+    pub(crate) fn nullifying_refs<O>(context: &RefCell<Self>, targets: HashSet<ID>, f: impl FnOnce() -> O) -> O {
+        let prev = std::mem::replace(&mut context.borrow_mut().nullified, targets);
+        let result = f();
+        context.borrow_mut().nullified = prev;
+        result
+    }
+
+    /// The target that a clone of a `NodeRef` to `target` should have: the copy if the
+    /// target is being deep copied with remapping, nothing if the reference is being
+    /// nullified, and `target` otherwise.
+    pub(crate) fn cloned_ref(context: &RefCell<Self>, target: Option<ID>, policy: RefPolicy) -> Option<ID> {
+        let target = target?;
+        let mut context = context.borrow_mut();
+        if let Some(refs) = &mut context.enumerated_refs {
+            refs.push((target, policy));
+        }
+        if context.nullified.contains(&target) {
+            return None
+        }
+        Some(context.new_ids.get(&target).copied().unwrap_or(target))
+    }
+
+    /// Copy `node`, with its `Child`ren with the given IDs pointing to the given nodes instead.
+    fn copy_replacing(context: &RefCell<Self>, node: &dyn NodeClone, replacements: HashMap<ID, Rc<dyn NodeClone>>)
+        -> Rc<dyn NodeClone> {
+        let expected = replacements.len();
+        let prev_status = {
+            let mut context = context.borrow_mut();
+            context.replacements = replacements;
+            std::mem::replace(&mut context.status, ContextStatus::Propagation)
+        };
+        let new_node = if node.implements_poll_all() {
+            // copy without polling any children, then poll them all
+            let mut new_node = Context::suspend(context, || node.dyn_clone());
+            Rc::get_mut(&mut new_node).unwrap().poll_all_mut();
+            new_node
+        } else {
+            // clones cause polls
+            node.dyn_clone()
+        };
+        let unused = {
+            let mut context = context.borrow_mut();
+            context.status = prev_status;
+            if context.replaced.len() != expected {
+                context.fail(CodError::ChildNotFound { parent: node.header().id });
+            }
+            context.replaced.clear();
+            std::mem::take(&mut context.replacements)
+        };
+        // dropped after the borrow ends, since dropping nodes may poll
+        drop(unused);
+        new_node
+    }
+
+    /// Register the removal of the subtree of `node`. Pending nodes are kept on the heap,
+    /// so deep trees do not overflow the execution stack.
+    fn remove(context: &RefCell<Self>, node: Rc<dyn NodeClone>) {
+        let mut stack = vec![node];
+        while let Some(node) = stack.pop() {
+            context.borrow_mut().node_map_erase(node.header().id);
+            stack.extend(Context::children(context, &*node));
+        }
+    }
+
+    /// Run `f` with the context deactivated, so that `Child`ren cloned or dropped
+    /// inside it are not noticed.
+    pub(crate) fn suspend<O>(context: &RefCell<Self>, f: impl FnOnce() -> O) -> O {
+        let prev_status = std::mem::replace(&mut context.borrow_mut().status, ContextStatus::Inactive);
+        let result = f();
+        context.borrow_mut().status = prev_status;
+        result
+    }
+
+    /// Find the direct children of `node`, using the same reflection as removal.
+    /// Can be called in any state of the context, and leaves it unchanged.
+    pub(crate) fn children(context: &RefCell<Self>, node: &dyn NodeClone) -> Vec<Rc<dyn NodeClone>> {
+        Context::enumerate(context, || {
+            if node.implements_poll_all() {
+                node.poll_all();
+            } else {
+                // namesake of this crate: Clone on Drop
+                // let me explain.
+                // when cleaning up the node pointed to by a `Child`, we need to iterate over
+                // its subtree. we abuse Clone as a form of reflection to find the children
+                // of this node.
+                node.cod();
+            }
+        })
+    }
+
+    /// Collect the `Child`ren polled in `poll`. Can be called in any state of the
+    /// context, and leaves it unchanged.
+    pub(crate) fn enumerate(context: &RefCell<Self>, poll: impl FnOnce()) -> Vec<Rc<dyn NodeClone>> {
+        let (prev_status, prev_enumerated) = {
+            let mut context = context.borrow_mut();
+            let prev_status = std::mem::replace(&mut context.status, ContextStatus::Enumeration);
+            (prev_status, std::mem::take(&mut context.enumerated))
+        };
+        poll();
+        let mut context = context.borrow_mut();
+        context.status = prev_status;
+        std::mem::replace(&mut context.enumerated, prev_enumerated)
+    }
+
+    /// The targets of the `NodeRef`s in `node`, found by cloning it. Can be called in
+    /// any state of the context, and leaves it unchanged.
+    pub(crate) fn refs(context: &RefCell<Self>, node: &dyn NodeClone) -> Vec<(ID, RefPolicy)> {
+        let prev_refs = context.borrow_mut().enumerated_refs.replace(Vec::new());
+        // the clone must not copy any `Child`ren
+        Context::enumerate(context, || node.cod());
+        std::mem::replace(&mut context.borrow_mut().enumerated_refs, prev_refs).unwrap()
+    }
+
+    /// Record `error` if it is the first one, and carry on as well as possible.
+    fn fail(&mut self, error: CodError) -> Option<Rc<dyn NodeClone>> {
+        self.error.get_or_insert(error);
+        None
+    }
+
     fn node_map_update(&mut self, id: ID, node: &Rc<dyn NodeClone>) {
-        self.updates.push(IDMapUpdate::Set(id, Rc::downgrade(node)));
+        self.updates.push(IDMapUpdate::Set(id, Rc::downgrade(node), Any::type_id(&**node)));
+        self.copied.insert(id);
     }
 
     fn node_map_erase(&mut self, id: ID) {
         self.updates.push(IDMapUpdate::Erase(id));
     }
 
-    pub(crate) fn begin_mutate(context: &RefCell<Self>) {
-        let mut context = context.borrow_mut();
-        assert!(matches!(context.status, ContextStatus::Inactive));
-        context.status = ContextStatus::Mutation(TraversalStatus::Inactive);
+    pub(crate) fn mutation_session_active(context: &RefCell<Self>) -> bool {
+        matches!(context.borrow().status, ContextStatus::Mutation)
     }
 
-    pub(crate) fn mutation_session_active(context: &RefCell<Self>) -> bool {
-        matches!(context.borrow().status, ContextStatus::Mutation(_))
+    /// Whether the node with the given ID was created or copied during the current
+    /// mutation session, and registered already.
+    pub(crate) fn copied_in_session(context: &RefCell<Self>, id: ID) -> bool {
+        let context = context.borrow();
+        matches!(context.status, ContextStatus::Mutation) && context.copied.contains(&id)
     }
 
-    pub(crate) fn end_mutate(context: &RefCell<Self>) -> impl Iterator<Item=IDMapUpdate> {
+    /// If an error was encountered during the session, the updates are discarded.
+    fn end_mutate(context: &RefCell<Self>)
+        -> Result<impl Iterator<Item=IDMapUpdate>, CodError> {
         let mut context = context.borrow_mut();
-        assert!(matches!(context.status, ContextStatus::Mutation(TraversalStatus::Inactive)));
+        assert!(matches!(context.status, ContextStatus::Mutation));
         context.status = ContextStatus::Inactive;
         let updates = std::mem::take(&mut context.updates);
+        match context.error.take() {
+            Some(error) => Err(error),
+            None => Ok(updates.into_iter()),
+        }
+    }
+
+    /// Take the map updates made so far in the current mutation session.
+    fn take_updates(context: &RefCell<Self>) -> impl Iterator<Item=IDMapUpdate> {
+        let mut context = context.borrow_mut();
+        assert!(matches!(context.status, ContextStatus::Mutation));
+        let updates = std::mem::take(&mut context.updates);
         updates.into_iter()
     }
 
-    pub(crate) fn set_replacement(context: &RefCell<Self>, replacement: Replacement) {
+    /// Until [`Context::finish_replacement`], polled `Child`ren with one of the given IDs
+    /// will be replaced with the corresponding node.
+    pub(crate) fn set_replacements(context: &RefCell<Self>, replacements: HashMap<ID, Rc<dyn NodeClone>>) {
         let mut context = context.borrow_mut();
         assert!(matches!(context.status, ContextStatus::Inactive));
-        context.status = ContextStatus::Propagation(replacement, false);
+        context.status = ContextStatus::Propagation;
+        context.replacements = replacements;
     }
 
-    pub(crate) fn finish_replacement(context: &RefCell<Self>) -> bool {
-        let mut context = context.borrow_mut();
-        let result = match context.status {
-            ContextStatus::Propagation(_, found) => found,
-            _ => panic!()
+    /// Where the replacement for the `Child` with the given ID was last seen in its
+    /// parent's container, see [`ChildContainer::set_slot_hint`](crate::ChildContainer::set_slot_hint).
+    pub(crate) fn slot_hint(context: &RefCell<Self>, id: ID) -> Option<usize> {
+        let context = context.borrow();
+        match context.status {
+            ContextStatus::Propagation => Some(context.replacements.get(&id)?.header().slot.get()),
+            _ => None,
+        }
+    }
+
+    /// Fails if an error was encountered, or not all replacements were made.
+    pub(crate) fn finish_replacement(context: &RefCell<Self>, parent_id: ID) -> Result<(), CodError> {
+        let (result, unused) = {
+            let mut context = context.borrow_mut();
+            assert!(matches!(context.status, ContextStatus::Propagation));
+            let all_replaced = context.replaced.len() == context.replacements.len();
+            context.replaced.clear();
+            context.status = ContextStatus::Inactive;
+            let result = match context.error.take() {
+                Some(error) => Err(error),
+                None if !all_replaced => Err(CodError::ChildNotFound { parent: parent_id }),
+                None => Ok(()),
+            };
+            (result, std::mem::take(&mut context.replacements))
         };
-        context.status = ContextStatus::Inactive;
+        // dropped after the borrow ends, since dropping nodes may poll
+        drop(unused);
         result
     }
+}
 
-    pub(crate) fn end_replacement(context: &RefCell<Self>) -> impl Iterator<Item=IDMapUpdate> {
-        let mut context = context.borrow_mut();
-        assert!(matches!(context.status, ContextStatus::Inactive));
-        let updates = std::mem::take(&mut context.updates);
-        updates.into_iter()
+/// A mutation session, with a context of its own. Each `MutRef`, `Transaction`
+/// and construction of a `State` has one, so sessions may be nested or interleaved.
+///
+/// `Child`ren cloned or dropped are noticed by the current session, which is the
+/// live one that was most recently begun or activated.
+pub(crate) struct Session {
+    context: std::rc::Rc<RefCell<Context>>,
+}
+
+impl Session {
+    /// Begin a mutation session and make it current.
+    pub(crate) fn begin() -> Self {
+        let session = Session::idle();
+        session.context.borrow_mut().status = ContextStatus::Mutation;
+        session
+    }
+
+    /// A current session in which nothing is noticed, for work outside mutation
+    /// sessions that must not be seen by other live sessions.
+    pub(crate) fn idle() -> Self {
+        let session = Session {
+            context: Default::default(),
+        };
+        session.activate();
+        session
+    }
+
+    /// Make this the current session.
+    pub(crate) fn activate(&self) {
+        SESSIONS.with(|sessions| {
+            let mut sessions = sessions.borrow_mut();
+            if !sessions.last().is_some_and(|last| std::rc::Rc::ptr_eq(last, &self.context)) {
+                sessions.retain(|context| !std::rc::Rc::ptr_eq(context, &self.context));
+                sessions.push(std::rc::Rc::clone(&self.context));
+            }
+        });
+    }
+
+    /// Take the map updates made so far in the session.
+    pub(crate) fn take_updates(&self) -> impl Iterator<Item=IDMapUpdate> {
+        Context::take_updates(&self.context)
+    }
+
+    /// End the session, returning its map updates. If an error was encountered
+    /// during it, the updates are discarded.
+    pub(crate) fn end(&self) -> Result<impl Iterator<Item=IDMapUpdate>, CodError> {
+        Context::end_mutate(&self.context)
+    }
+
+    /// Forget the updates of the session, and make it current but inactive,
+    /// so that nodes dropped after this are not noticed.
+    pub(crate) fn abort(&self) {
+        self.activate();
+        // dropped after the borrow ends, since dropping nodes may poll
+        let _aborted = std::mem::take(&mut *self.context.borrow_mut());
     }
 }
 
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.abort();
+        SESSIONS.with(|sessions| sessions.borrow_mut().pop());
+    }
+}
@@ -4,7 +4,7 @@ use std::any::TypeId;
 /// Replicates Rc::downcast, except for our custom trait.
 /// Relies on any::TypeId for correctness.
 pub(crate) fn downcast_rc<T: NodeClone>(rc: Rc<dyn NodeClone>) -> Option<Rc<T>> {
-    if (&*rc).type_id() == TypeId::of::<T>() {
+    if (*rc).type_id() == TypeId::of::<T>() {
         let ptr = Rc::into_raw(rc);
         let ptr: *const T = ptr as *const T;
         unsafe { Some(Rc::from_raw(ptr)) }
@@ -12,3 +12,13 @@ pub(crate) fn downcast_rc<T: NodeClone>(rc: Rc<dyn NodeClone>) -> Option<Rc<T>>
         None
     }
 }
+
+/// Replicates `<dyn Any>::downcast_mut`, except for our custom trait.
+pub(crate) fn downcast_mut<T: NodeClone>(node: &mut dyn NodeClone) -> Option<&mut T> {
+    if (*node).type_id() == TypeId::of::<T>() {
+        let ptr: *mut T = node as *mut dyn NodeClone as *mut T;
+        unsafe { Some(&mut *ptr) }
+    } else {
+        None
+    }
+}
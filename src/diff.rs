@@ -0,0 +1,93 @@
+//! Structural comparison of two states.
+
+use std::collections::HashMap;
+use crate::{NodeClone, State, ID, Rc};
+use crate::context::{with_context, Context};
+
+/// Nodes that differ between two versions of a [`State`], see [`State::diff`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Diff {
+    /// Nodes that only exist in the new state.
+    pub added: Vec<ID>,
+    /// Nodes that only exist in the old state.
+    pub removed: Vec<ID>,
+    /// Nodes that exist in both states, but were replaced with a different version.
+    ///
+    /// Because mutations propagate, this includes all ancestors of changed nodes.
+    pub modified: Vec<ID>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+enum Task {
+    Compare(Rc<dyn NodeClone>, Rc<dyn NodeClone>),
+    Added(Rc<dyn NodeClone>),
+    Removed(Rc<dyn NodeClone>),
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// Find out which nodes were added, removed or modified going from `old` to `new`.
+    ///
+    /// Subtrees that are shared between the two states are skipped, so the cost
+    /// is proportional to the size of the change rather than the size of the tree.
+    /// Nodes that were moved to a different parent are reported as modified.
+    /// Each list is sorted by ID.
+    pub fn diff(old: &State<R>, new: &State<R>) -> Diff {
+        let mut diff = Diff::default();
+        let old_root = Rc::clone(&old.root) as Rc<dyn NodeClone>;
+        let new_root = Rc::clone(&new.root) as Rc<dyn NodeClone>;
+        let mut stack = if old_root.header().id == new_root.header().id {
+            vec![Task::Compare(old_root, new_root)]
+        } else {
+            vec![Task::Removed(old_root), Task::Added(new_root)]
+        };
+        while let Some(task) = stack.pop() {
+            match task {
+                Task::Compare(old_node, new_node) => {
+                    if Rc::ptr_eq(&old_node, &new_node) { continue }
+                    diff.modified.push(new_node.header().id);
+                    let mut old_children: HashMap<ID, Rc<dyn NodeClone>> = children(&*old_node)
+                        .into_iter().map(|child| (child.header().id, child)).collect();
+                    for new_child in children(&*new_node) {
+                        let id = new_child.header().id;
+                        if let Some(old_child) = old_children.remove(&id) {
+                            stack.push(Task::Compare(old_child, new_child));
+                        } else {
+                            stack.push(Task::Added(new_child));
+                        }
+                    }
+                    stack.extend(old_children.into_values().map(Task::Removed));
+                },
+                Task::Added(new_node) => {
+                    let id = new_node.header().id;
+                    if let Some(old_node) = old.ref_from_id(id) {
+                        // moved here from elsewhere
+                        stack.push(Task::Compare(old_node, new_node));
+                        continue
+                    }
+                    diff.added.push(id);
+                    stack.extend(children(&*new_node).into_iter().map(Task::Added));
+                },
+                Task::Removed(old_node) => {
+                    let id = old_node.header().id;
+                    // if moved elsewhere, it is compared at the new location
+                    if new.id_lookup.contains_key(&id) { continue }
+                    diff.removed.push(id);
+                    stack.extend(children(&*old_node).into_iter().map(Task::Removed));
+                },
+            }
+        }
+        diff.added.sort_unstable();
+        diff.removed.sort_unstable();
+        diff.modified.sort_unstable();
+        diff
+    }
+}
+
+fn children(node: &dyn NodeClone) -> Vec<Rc<dyn NodeClone>> {
+    with_context(|c| Context::children(c, node))
+}
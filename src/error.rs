@@ -0,0 +1,132 @@
+//! Error types.
+
+use std::fmt;
+use crate::ID;
+
+/// Why a node could not be found by [`State::try_get`](crate::State::try_get).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LookupError {
+    /// There is no node with this ID in the state.
+    NotFound(ID),
+    /// The node exists, but is not of the requested type.
+    WrongType {
+        id: ID,
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+impl fmt::Display for LookupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LookupError::NotFound(id) => write!(f, "no node with ID {}", id),
+            LookupError::WrongType { id, expected, found } => {
+                write!(f, "node with ID {} is a `{}`, not a `{}`", id, found, expected)
+            },
+        }
+    }
+}
+
+impl std::error::Error for LookupError {}
+
+/// Misuse of Cod, or a corrupted tree. Returned by the `try_` methods, such as
+/// [`State::try_get_mut`](crate::State::try_get_mut). The other methods panic instead.
+///
+/// When an error is returned, the state is left unchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodError {
+    /// The node passed to `get_mut` is not part of the state.
+    NotInState(ID),
+    /// The `Child` pointing to this node was polled with `poll()` where `poll_mut()`
+    /// was expected. Usually `poll_all` is called from `poll_all_mut`.
+    PollNotMut(ID),
+    /// The `Child` pointing to this node was polled or mutated at a point where Cod
+    /// does not expect it, for example from within the polling methods of `Node`.
+    UnexpectedPoll(ID),
+    /// The same ID was found in multiple `Child`s, so the state is corrupted.
+    DuplicateID(ID),
+    /// An edited node could not be found among the `Child`ren of its parent
+    /// while updating its ancestors. Its parent ID is likely wrong.
+    ChildNotFound { parent: ID },
+    /// The node could not be taken out of its parent, because it is the root or
+    /// not in an `Option`, `Vec` or `im::Vector`.
+    CannotRemove(ID),
+    /// A node was removed while another node refers to it with [`RefPolicy::Reject`](crate::RefPolicy::Reject).
+    StillReferenced { id: ID, referrer: ID },
+    /// The node cannot be moved into its own subtree.
+    MoveIntoSubtree(ID),
+    /// An edited node has no parent ID, but is not the root. It was created with
+    /// [`Child::new`](crate::Child::new) in a parent that does not report its children.
+    Detached(ID),
+    /// The node was edited in a transaction with [`Transaction::get_mut`](crate::Transaction::get_mut),
+    /// and afterwards through `Child::make_mut` on its parent. One of the edits would be lost.
+    EditedTwice(ID),
+    /// The node got a new version in the mutation session of one edit, but belongs to
+    /// another. This happens when a `&mut` borrowed from one `MutRef` is used after
+    /// another `MutRef` was dereferenced.
+    WrongSession(ID),
+}
+
+impl fmt::Display for CodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodError::NotInState(id) => write!(f, "node with ID {} is not part of the state", id),
+            CodError::PollNotMut(id) => {
+                write!(f, "`poll()` called when `poll_mut()` was expected, on node with ID {}", id)
+            },
+            CodError::UnexpectedPoll(id) => write!(f, "node with ID {} was polled in an unexpected context", id),
+            CodError::DuplicateID(id) => {
+                write!(f, "the ID {} was found in multiple `Child`s, state is corrupted", id)
+            },
+            CodError::ChildNotFound { parent } => {
+                write!(f, "could not find associated `Child` in node with ID {} while traversing up", parent)
+            },
+            CodError::CannotRemove(id) => write!(f, "node with ID {} cannot be taken out of its parent", id),
+            CodError::StillReferenced { id, referrer } => {
+                write!(f, "node with ID {} was removed, but node with ID {} still refers to it", id, referrer)
+            },
+            CodError::MoveIntoSubtree(id) => write!(f, "node with ID {} cannot be moved into its own subtree", id),
+            CodError::Detached(id) => write!(f, "node with ID {} has no parent, but is not the root", id),
+            CodError::EditedTwice(id) => {
+                write!(f, "node with ID {} was edited through `make_mut` after `Transaction::get_mut`", id)
+            },
+            CodError::WrongSession(id) => {
+                write!(f, "node with ID {} was created or copied in the mutation session of another edit", id)
+            },
+        }
+    }
+}
+
+impl std::error::Error for CodError {}
+
+/// Why a structural edit, such as [`State::move_node`](crate::State::move_node), failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditError {
+    /// A node taking part in the edit could not be found.
+    Lookup(LookupError),
+    /// The edit is not possible, or was rejected when committing.
+    Cod(CodError),
+}
+
+impl From<LookupError> for EditError {
+    fn from(error: LookupError) -> Self {
+        EditError::Lookup(error)
+    }
+}
+
+impl From<CodError> for EditError {
+    fn from(error: CodError) -> Self {
+        EditError::Cod(error)
+    }
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::Lookup(error) => error.fmt(f),
+            EditError::Cod(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
@@ -0,0 +1,183 @@
+//! Linear undo/redo on top of [`State`].
+//!
+//! Since states are persistent, a history is just a list of old states.
+
+use std::collections::VecDeque;
+use crate::{NodeClone, State, MutRef, Rc};
+
+/// Keeps track of previous versions of a [`State`], allowing undo and redo.
+///
+/// Every mutation session started through the history (with [`get_mut`](History::get_mut)
+/// or [`edit`](History::edit)) becomes one undo step, unless it is part of a group.
+/// Sessions that leave the state as it was, for example because they panicked, are skipped.
+pub struct History<R: NodeClone + Clone> {
+    state: State<R>,
+    /// The state before the last session, until it is known whether the session changed it
+    pending: Option<State<R>>,
+    undo_stack: VecDeque<State<R>>,
+    redo_stack: Vec<State<R>>,
+    max_depth: Option<usize>,
+    group_depth: usize,
+    group_recorded: bool,
+}
+
+impl<R: NodeClone + Clone> History<R> {
+    /// Start a history with `state` as the current state. There is nothing to undo.
+    pub fn new(state: State<R>) -> Self {
+        History {
+            state,
+            pending: None,
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            max_depth: None,
+            group_depth: 0,
+            group_recorded: false,
+        }
+    }
+
+    /// Limit how many undo steps are kept. The oldest steps are forgotten first.
+    /// `None` means no limit, which is the default.
+    pub fn set_max_depth(&mut self, max_depth: Option<usize>) {
+        self.settle();
+        self.max_depth = max_depth;
+        self.enforce_max_depth();
+    }
+
+    pub fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    pub fn state(&self) -> &State<R> {
+        &self.state
+    }
+
+    pub fn root(&self) -> &R {
+        self.state.root()
+    }
+
+    pub fn root_ref(&self) -> Rc<R> {
+        self.state.root_ref()
+    }
+
+    /// Like [`State::get_mut`], but the state before the mutation is recorded
+    /// as an undo step, if the commit changes it.
+    pub fn get_mut<T: NodeClone + Clone>(&mut self, node: Rc<T>) -> MutRef<'_, R, T> {
+        self.record();
+        self.state.get_mut(node)
+    }
+
+    /// Perform arbitrary modifications on the current state as one undo step.
+    pub fn edit<O>(&mut self, edit: impl FnOnce(&mut State<R>) -> O) -> O {
+        self.record();
+        edit(&mut self.state)
+    }
+
+    /// Everything done to the history inside `group` becomes a single undo step.
+    /// Groups may be nested, in which case the outermost group determines the step.
+    pub fn group<O>(&mut self, group: impl FnOnce(&mut Self) -> O) -> O {
+        self.begin_group();
+        let result = group(self);
+        self.end_group();
+        result
+    }
+
+    /// Manual version of [`group`](History::group). Every call must be paired with
+    /// a call to [`end_group`](History::end_group).
+    pub fn begin_group(&mut self) {
+        if self.group_depth == 0 {
+            self.group_recorded = false;
+        }
+        self.group_depth += 1;
+    }
+
+    pub fn end_group(&mut self) {
+        assert!(self.group_depth > 0, "Cod: `end_group()` called without matching `begin_group()`");
+        self.group_depth -= 1;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo_stack.is_empty() || self.pending.as_ref().is_some_and(|pending| self.changed_since(pending))
+    }
+
+    pub fn can_redo(&self) -> bool {
+        // a pending change discards the redo steps once settled
+        !self.redo_stack.is_empty() && !self.pending.as_ref().is_some_and(|pending| self.changed_since(pending))
+    }
+
+    /// Go back to the state before the last undo step. Returns false if there
+    /// was nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        self.settle();
+        match self.undo_stack.pop_back() {
+            Some(state) => {
+                let current = std::mem::replace(&mut self.state, state);
+                self.redo_stack.push(current);
+                // an open group should record a fresh step after undoing
+                self.group_recorded = false;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Reapply the last undone step. Returns false if there was nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        self.settle();
+        match self.redo_stack.pop() {
+            Some(state) => {
+                let current = std::mem::replace(&mut self.state, state);
+                self.undo_stack.push_back(current);
+                self.group_recorded = false;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Forget all undo and redo steps, keeping the current state.
+    pub fn clear(&mut self) {
+        self.pending = None;
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+
+    fn record(&mut self) {
+        if self.group_depth > 0 {
+            if self.group_recorded { return }
+            self.group_recorded = true;
+        }
+        self.settle();
+        self.pending = Some(self.state.clone());
+    }
+
+    /// Turn the pending state into an undo step if the state has changed since.
+    fn settle(&mut self) {
+        let Some(pending) = self.pending.take() else { return };
+        if self.changed_since(&pending) {
+            self.redo_stack.clear();
+            self.undo_stack.push_back(pending);
+            self.enforce_max_depth();
+        } else if self.group_depth > 0 {
+            // the next session of the group is recorded instead
+            self.group_recorded = false;
+        }
+    }
+
+    fn changed_since(&self, previous: &State<R>) -> bool {
+        !Rc::ptr_eq(&previous.root, &self.state.root)
+    }
+
+    fn enforce_max_depth(&mut self) {
+        if let Some(max_depth) = self.max_depth {
+            while self.undo_stack.len() > max_depth {
+                self.undo_stack.pop_front();
+            }
+        }
+    }
+}
+
+impl<R: NodeClone + Clone> From<State<R>> for History<R> {
+    fn from(state: State<R>) -> Self {
+        History::new(state)
+    }
+}
@@ -4,8 +4,22 @@ pub type ID = u64;
 
 static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
 
+/// Never returns `ID::MAX`, which stands for a missing parent ID.
 pub(crate) fn new_id() -> ID {
-    ID_COUNTER.fetch_add(1, Ordering::Relaxed)
+    let id = ID_COUNTER.fetch_add(1, Ordering::Relaxed);
+    assert!(id != ID::MAX, "Cod: ran out of IDs");
+    id
 }
 
-
+/// Make sure `id` is never returned by `new_id`, e.g. because it was loaded from a file.
+/// Returns false for `ID::MAX`, which cannot be reserved.
+#[cfg(feature = "serde")]
+pub(crate) fn reserve_id(id: ID) -> bool {
+    match id.checked_add(1) {
+        Some(next) => {
+            ID_COUNTER.fetch_max(next, Ordering::Relaxed);
+            true
+        },
+        None => false,
+    }
+}
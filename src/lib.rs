@@ -1,42 +1,177 @@
 use std::ops::{Deref, DerefMut};
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::any::Any;
 use std::fmt::Debug;
 use std::fmt;
+use std::collections::HashMap;
 
 pub use cod_node_derive::Node;
 
 mod id;
 mod context;
 mod danger_zone;
+mod history;
+mod undo_tree;
+mod diff;
+mod error;
+mod transaction;
+mod navigation;
+mod walk;
+mod type_index;
+mod node_ref;
+mod backlinks;
+#[cfg(feature = "serde")]
+mod serde_impls;
 #[cfg(test)]
 mod test;
+// lets the tests use the derive, which refers to `cod::`
+#[cfg(test)]
+extern crate self as cod;
 
 pub use id::ID;
 use id::new_id;
 
-use context::{CONTEXT, Context, PollReason, Replacement, IDMapUpdate};
+pub use history::History;
+pub use undo_tree::{UndoTree, Checkpoint, CheckpointID};
+pub use diff::Diff;
+pub use error::{LookupError, CodError, EditError};
+pub use transaction::Transaction;
+pub use navigation::Ancestors;
+pub use walk::Walk;
+pub use node_ref::{NodeRef, RefPolicy};
+
+use context::{with_context, drop_iteratively, Context, Session, PollReason, IDMapUpdate};
 
 use danger_zone::downcast_rc;
+use type_index::TypeIndex;
+use backlinks::Backlinks;
 
-/// Can be changed to Arc later. However, the design is not thread-aware
-/// when mutating. So appropriate !Send/!Syncs need to be defined before changing.
+/// With the `sync` feature, this is `Arc` instead, and states can be sent to
+/// other threads if all node types are `Send + Sync`. Mutation is still not
+/// thread-aware: each mutation session happens on a single thread.
+#[cfg(not(feature = "sync"))]
 pub use std::rc::Rc as Rc;
+#[cfg(not(feature = "sync"))]
 pub use std::rc::Weak as Weak;
+#[cfg(not(feature = "sync"))]
 pub use im_rc as im;
 
-#[derive(Clone, Debug)]
+#[cfg(feature = "sync")]
+pub use std::sync::Arc as Rc;
+#[cfg(feature = "sync")]
+pub use std::sync::Weak as Weak;
+#[cfg(feature = "sync")]
+pub use ::im;
+
+/// Bounds required of all nodes. With the `sync` feature, this is `Send + Sync`,
+/// otherwise it is implemented for all types.
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+#[cfg(feature = "sync")]
+pub trait MaybeSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: ?Sized + Send + Sync> MaybeSync for T {}
+
+#[derive(Clone)]
 pub struct Header {
     id: ID,
-    parent_id: Option<ID>,
+    parent_id: ParentSlot,
+    slot: SlotHint,
 }
 
 impl Header {
     pub fn new() -> Self {
         Header {
             id: new_id(),
-            parent_id: None
+            parent_id: ParentSlot::default(),
+            slot: SlotHint::default(),
+        }
+    }
+
+    pub fn id(&self) -> ID {
+        self.id
+    }
+
+    /// `None` for the root, and for nodes not yet attached to a parent.
+    pub fn parent_id(&self) -> Option<ID> {
+        self.parent_id.get()
+    }
+
+    /// Takes `&self`, so that nodes created with [`Child::new`] can be attached after
+    /// they are shared.
+    pub(crate) fn set_parent_id(&self, parent_id: Option<ID>) {
+        self.parent_id.set(parent_id);
+    }
+}
+
+impl Debug for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Header")
+            .field("id", &self.id)
+            .field("parent_id", &self.parent_id())
+            .finish()
+    }
+}
+
+/// Index of a node in the container of its parent when last seen, so that
+/// propagation can find it without scanning all of its siblings.
+/// Only a hint: it is not updated when the container changes.
+#[derive(Default)]
+struct SlotHint(AtomicUsize);
+
+impl SlotHint {
+    fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set(&self, index: usize) {
+        self.0.store(index, Ordering::Relaxed);
+    }
+}
+
+impl Clone for SlotHint {
+    fn clone(&self) -> Self {
+        SlotHint(AtomicUsize::new(self.get()))
+    }
+}
+
+/// Parent ID of a node. Only changed while the node is shared when it was created
+/// detached with [`Child::new`], and is being attached to its parent.
+struct ParentSlot(AtomicU64);
+
+/// Stands for `None` in a `ParentSlot`. Never used as an ID: `new_id` does not hand it
+/// out, and loading it is rejected by `reserve_id`.
+const NO_PARENT: ID = ID::MAX;
+
+impl ParentSlot {
+    fn get(&self) -> Option<ID> {
+        match self.0.load(Ordering::Relaxed) {
+            NO_PARENT => None,
+            id => Some(id),
         }
     }
+
+    fn set(&self, parent_id: Option<ID>) {
+        self.0.store(parent_id.unwrap_or(NO_PARENT), Ordering::Relaxed);
+    }
+}
+
+impl Default for ParentSlot {
+    fn default() -> Self {
+        ParentSlot(AtomicU64::new(NO_PARENT))
+    }
+}
+
+impl Clone for ParentSlot {
+    fn clone(&self) -> Self {
+        let slot = ParentSlot::default();
+        slot.set(self.get());
+        slot
+    }
 }
 
 impl Default for Header {
@@ -45,6 +180,14 @@ impl Default for Header {
     }
 }
 
+/// A node in the tree. Usually implemented with `#[derive(Node)]`, which also
+/// implements the optional polling methods for fields of type `Child<T>`, and
+/// `Option`, `Vec` or `im::Vector` thereof (see [`ChildContainer`]).
+///
+/// The derive only does so if the other fields are of types known to contain no
+/// `Child`ren, such as numbers, `String`s and collections of those. Mark fields of
+/// other types without `Child`ren with `#[cod(skip)]`, otherwise Cod falls back to
+/// finding the children through `Clone`.
 pub trait Node: 'static {
     fn header(&self) -> &Header;
     fn header_mut(&mut self) -> &mut Header;
@@ -58,7 +201,7 @@ pub trait Node: 'static {
     ///
     /// The implementaion should find the `Child` instance which corresponds to the
     /// given ID, and call `.poll_mut()` on it. You should not do anything else
-    /// with the `Child`s, doing so will **`panic!`**.
+    /// with the `Child`s, doing so results in a [`CodError`].
     /// 
     /// If you do implement this method, also make sure to implement `implements_poll_child`
     /// such that it returns true if you want it to be used on `self` specifically.
@@ -88,27 +231,55 @@ pub trait Node: 'static {
     /// call `.poll_mut()` on all `Child` instances associated with this node.
     fn poll_all_mut(&mut self) { }
     fn implements_poll_all(&self) -> bool { false }
+
+    /// Optional: Take the `Child` with the given ID out of this node, and return its node.
+    /// Implemented by `#[derive(Node)]` for `Child`ren in an `Option`, `Vec` or `im::Vector`,
+    /// see [`ChildContainer::take_child`]. A plain `Child` field cannot be taken.
+    ///
+    /// Cod uses this to remove nodes without knowing the fields of their parent.
+    /// The node is detached without being noticed by the mutation session.
+    fn take_child(&mut self, _id: ID) -> Option<Rc<dyn NodeClone>> { None }
+
+    /// Call `f` on each direct child of this node, in order. Implemented by `#[derive(Node)]`
+    /// for the child fields it recognizes.
+    ///
+    /// The default implementation uses `poll_all`, so it finds nothing if that is not
+    /// implemented either. [`State::walk`] finds the children of all nodes.
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn NodeClone)) {
+        if self.implements_poll_all() {
+            for child in with_context(|c| Context::enumerate(c, || self.poll_all())) {
+                f(&*child);
+            }
+        }
+    }
 }
 
 /// This is a wrapper trait for `Node` which enables cloning through dynamic dispatch and RTTI.
-/// It will be automatically implemented for any struct that is `Node + Clone`.
-pub trait NodeClone: Node + Any {
+/// It will be automatically implemented for any struct that is `Node + Clone`
+/// (and `Send + Sync` with the `sync` feature).
+pub trait NodeClone: Node + Any + MaybeSync {
     fn dyn_clone(&self) -> Rc<dyn NodeClone>;
     /// clone, then immediately drop. used for reflection
     fn cod(&self);
+    /// Name of the concrete type, for diagnostics.
+    fn type_name(&self) -> &'static str;
 }
 
-impl<T: Node + Clone> NodeClone for T {
+impl<T: Node + Clone + MaybeSync> NodeClone for T {
     fn dyn_clone(&self) -> Rc<dyn NodeClone> {
         Rc::new(self.clone())
     }
     fn cod(&self) {
         let _ = self.clone();
     }
+    fn type_name(&self) -> &'static str {
+        std::any::type_name::<T>()
+    }
 }
 
 pub struct Child<T: NodeClone> {
-    inner_ref: Rc<T>,
+    /// Only dropped manually, see the `Drop` impl
+    inner_ref: ManuallyDrop<Rc<T>>,
 }
 
 pub struct ParentID(ID);
@@ -124,40 +295,80 @@ impl<P: Node> From<&P> for ParentID {
 }
 
 impl<T: NodeClone + Clone> Child<T> {
+    /// A detached `Child`, whose parent ID is filled in when the parent is constructed
+    /// into a [`State`], or when the edit adding it is committed. The children of the
+    /// parent are found with [`Node::for_each_child`] if it implements `poll_all`, as
+    /// `#[derive(Node)]` does, and otherwise by cloning it.
+    ///
+    /// Until then, [`Header::parent_id`] of the node is `None`.
+    pub fn new(mut node: T) -> Self {
+        node.header_mut().set_parent_id(None);
+        Self::attach(node)
+    }
+
     pub fn with_parent(parent: impl Into<ParentID>, node: T) -> Self {
         Self::with_parent_id(parent.into().0, node)
     }
 
     fn with_parent_id(parent_id: ID, mut node: T) -> Self {
-        node.header_mut().parent_id = Some(parent_id);
+        node.header_mut().set_parent_id(Some(parent_id));
+        Self::attach(node)
+    }
+
+    fn attach(node: T) -> Self {
         let rc = Rc::new(node);
         let child = Self {
-            inner_ref: rc.clone()
+            inner_ref: ManuallyDrop::new(rc.clone())
         };
-        CONTEXT.with(|c| {
+        with_context(|c| {
             Context::poll(c, PollReason::Construct, rc);
         });
         child
     }
 
-    /// TODO. avoid new clone if child has already been accessed during this mutation session.
+    /// Get a mutable reference to the node, copying it if it is shared.
+    ///
+    /// During a mutation session, each node is copied at most once. Calling this
+    /// again on a node that was already copied or created in the session is cheap.
     pub fn make_mut(&mut self) -> MakeMutRef<'_, T> {
-        CONTEXT.with(|c| {
+        let register = with_context(|c| {
             if Context::mutation_session_active(c) {
+                // already copied, so needs no new copy if not shared
+                if Context::copied_in_session(c, self.get_id()) && Rc::strong_count(&self.inner_ref) == 1 {
+                    // only moves the node if the map update refers to it, without cloning
+                    let before = Rc::as_ptr(&self.inner_ref);
+                    Context::suspend(c, || { Rc::make_mut(&mut self.inner_ref); });
+                    return Rc::as_ptr(&self.inner_ref) != before
+                }
                 // let the context handle cloning (special stuff needs to happen)
-                if let Some(new_ref) =
-                    Context::poll_mut(c, PollReason::MakeMutPre, Rc::clone(&self.inner_ref)) {
-                    self.inner_ref = new_ref;
+                match Context::poll_mut(c, PollReason::MakeMutPre, Rc::clone(&self.inner_ref)) {
+                    Some(new_ref) => {
+                        let old_ref = std::mem::replace(&mut *self.inner_ref, new_ref);
+                        // if this was the last reference, the children now belong to the copy
+                        Context::suspend(c, || drop(old_ref));
+                    },
+                    // misuse, reported when the session ends. still uphold uniqueness
+                    None => Context::suspend(c, || { Rc::make_mut(&mut self.inner_ref); }),
                 }
+                true
             } else {
                 Rc::make_mut(&mut self.inner_ref);
+                false
             }
         });
         MakeMutRef {
-            child: self
+            child: self,
+            register,
         }
     }
 
+    /// Like `clone`, but the [`NodeRef`]s in the copied subtree that point to nodes
+    /// in it are changed to point to the corresponding copies. Other references are
+    /// left alone. Outside of a mutation session, this is the same as `clone`.
+    pub fn clone_remapping_refs(&self) -> Self {
+        with_context(|c| Context::remapping_refs(c, || self.clone()))
+    }
+
     pub fn get_ref(&self) -> Rc<T> {
         Rc::clone(&self.inner_ref)
     }
@@ -167,40 +378,351 @@ impl<T: NodeClone + Clone> Child<T> {
     }
     
     pub fn set_parent(&mut self, parent: impl Into<ParentID>) {
-        self.make_mut().header_mut().parent_id = Some(parent.into().0);
+        self.make_mut().header_mut().set_parent_id(Some(parent.into().0));
     }
 
     /// Deep clone and set new parent. If you do not need to change the parent,
     /// you may also use `.clone()` directly.
     pub fn deep_clone_to_parent(&self, parent: impl Into<ParentID>) -> Self {
         let mut child = Self {
-            inner_ref: Rc::clone(&self.inner_ref),
+            inner_ref: ManuallyDrop::new(Rc::clone(&self.inner_ref)),
         };
-        CONTEXT.with(|c| {
+        with_context(|c| {
             if let Some(new_ref) =
                 Context::poll_mut(c, PollReason::DeepCopy(parent.into().0), Rc::clone(&child.inner_ref)) {
-                child.inner_ref = new_ref;
+                *child.inner_ref = new_ref;
             }
         });
         child
     }
 
     pub fn poll(&self) {
-        CONTEXT.with(|c| {
+        with_context(|c| {
             Context::poll(c, PollReason::Manual, Rc::clone(&self.inner_ref));
         });
     }
 
     pub fn poll_mut(&mut self) {
-        CONTEXT.with(|c| {
+        with_context(|c| {
             if let Some(new_ref) =
                 Context::poll_mut(c, PollReason::ManualMut, Rc::clone(&self.inner_ref)) {
-                self.inner_ref = new_ref;
+                *self.inner_ref = new_ref;
             }
         });
     }
 }
 
+/// Types which contain `Child`ren, such as `Option<Child<T>>` or `Vec<Child<T>>`.
+///
+/// `#[derive(Node)]` uses this to implement the polling methods of [`Node`] for
+/// fields of these types. It is also convenient in manual implementations.
+pub trait ChildContainer {
+    /// Call `.poll()` on all contained `Child`s.
+    fn poll_all(&self);
+    /// Call `.poll_mut()` on all contained `Child`s.
+    fn poll_all_mut(&mut self);
+    /// Call `.poll_mut()` on the `Child` with the given ID, if there is one.
+    /// Returns whether it was found.
+    fn poll_child_mut(&mut self, id: ID) -> bool;
+    /// Optional: Like `poll_child_mut`, but only looks where the `Child` was last seen,
+    /// see [`set_slot_hint`](ChildContainer::set_slot_hint). Returns whether it was found.
+    ///
+    /// `#[derive(Node)]` tries this on all fields before `poll_child_mut`, so that
+    /// large containers are not scanned in vain.
+    fn poll_child_mut_hinted(&mut self, _id: ID) -> bool { false }
+    /// Whether a `Child` with the given ID is contained.
+    fn contains_child(&self, id: ID) -> bool;
+    /// Call `f` on the nodes of all contained `Child`s.
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn NodeClone));
+    /// Take the `Child` with the given ID out of the container and return its node,
+    /// if the container can do without it. This is not noticed by the mutation session.
+    fn take_child(&mut self, id: ID) -> Option<Rc<dyn NodeClone>>;
+    /// If this container is a single `Child`, take its node out without it being noticed.
+    fn into_node(self) -> Option<Rc<dyn NodeClone>> where Self: Sized;
+    /// Optional: Remember `index` as the position of the contained `Child`ren in an
+    /// enclosing container, such as a `Vec`. This lets `poll_child_mut` of the
+    /// enclosing container find them quickly.
+    fn set_slot_hint(&self, _index: usize) { }
+}
+
+/// How far from its remembered position a `Child` is looked for, see [`find_slot`].
+const SLOT_SEARCH_DISTANCE: usize = 16;
+
+/// Find the index of the element containing the `Child` with the given ID, by checking
+/// the positions around where it was last seen. Only possible during propagation.
+fn find_slot_near_hint<'a, C: ChildContainer + 'a>(id: ID, get: impl Fn(usize) -> Option<&'a C>)
+    -> Option<usize> {
+    let hint = with_context(|c| Context::slot_hint(c, id))?;
+    (0..=SLOT_SEARCH_DISTANCE)
+        .flat_map(|distance| [hint.checked_sub(distance), hint.checked_add(distance)])
+        .flatten()
+        .find(|&index| get(index).is_some_and(|element| element.contains_child(id)))
+}
+
+/// Find the index of the element containing the `Child` with the given ID, first with
+/// [`find_slot_near_hint`]. Otherwise all the elements are scanned, remembering their
+/// positions for next time.
+fn find_slot<'a, C: ChildContainer + 'a>(
+    id: ID,
+    get: impl Fn(usize) -> Option<&'a C>,
+    elements: impl Iterator<Item=&'a C>,
+) -> Option<usize> {
+    if let Some(index) = find_slot_near_hint(id, get) {
+        return Some(index)
+    }
+    let mut found = None;
+    for (index, element) in elements.enumerate() {
+        element.set_slot_hint(index);
+        if found.is_none() && element.contains_child(id) {
+            found = Some(index);
+        }
+    }
+    found
+}
+
+impl<T: NodeClone + Clone> ChildContainer for Child<T> {
+    fn poll_all(&self) {
+        self.poll();
+    }
+
+    fn poll_all_mut(&mut self) {
+        self.poll_mut();
+    }
+
+    fn poll_child_mut(&mut self, id: ID) -> bool {
+        if self.get_id() == id {
+            self.poll_mut();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn poll_child_mut_hinted(&mut self, id: ID) -> bool {
+        self.poll_child_mut(id)
+    }
+
+    fn contains_child(&self, id: ID) -> bool {
+        self.get_id() == id
+    }
+
+    fn set_slot_hint(&self, index: usize) {
+        self.inner_ref.header().slot.set(index);
+    }
+
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn NodeClone)) {
+        f(&**self.inner_ref);
+    }
+
+    fn take_child(&mut self, _id: ID) -> Option<Rc<dyn NodeClone>> {
+        None
+    }
+
+    fn into_node(self) -> Option<Rc<dyn NodeClone>> {
+        Some(self.into_inner())
+    }
+}
+
+impl<C: ChildContainer> ChildContainer for Option<C> {
+    fn poll_all(&self) {
+        if let Some(child) = self {
+            child.poll_all();
+        }
+    }
+
+    fn poll_all_mut(&mut self) {
+        if let Some(child) = self {
+            child.poll_all_mut();
+        }
+    }
+
+    fn poll_child_mut(&mut self, id: ID) -> bool {
+        match self {
+            Some(child) => child.poll_child_mut(id),
+            None => false,
+        }
+    }
+
+    fn poll_child_mut_hinted(&mut self, id: ID) -> bool {
+        match self {
+            Some(child) => child.poll_child_mut_hinted(id),
+            None => false,
+        }
+    }
+
+    fn contains_child(&self, id: ID) -> bool {
+        match self {
+            Some(child) => child.contains_child(id),
+            None => false,
+        }
+    }
+
+    fn set_slot_hint(&self, index: usize) {
+        if let Some(child) = self {
+            child.set_slot_hint(index);
+        }
+    }
+
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn NodeClone)) {
+        if let Some(child) = self {
+            child.for_each_child(f);
+        }
+    }
+
+    fn take_child(&mut self, id: ID) -> Option<Rc<dyn NodeClone>> {
+        let child = self.as_mut()?;
+        if !child.contains_child(id) {
+            return None
+        }
+        child.take_child(id).or_else(|| self.take()?.into_node())
+    }
+
+    fn into_node(self) -> Option<Rc<dyn NodeClone>> {
+        None
+    }
+}
+
+impl<C: ChildContainer> ChildContainer for Vec<C> {
+    fn poll_all(&self) {
+        for (index, child) in self.iter().enumerate() {
+            child.poll_all();
+            child.set_slot_hint(index);
+        }
+    }
+
+    fn poll_all_mut(&mut self) {
+        for (index, child) in self.iter_mut().enumerate() {
+            child.poll_all_mut();
+            child.set_slot_hint(index);
+        }
+    }
+
+    fn poll_child_mut(&mut self, id: ID) -> bool {
+        let index = find_slot(id, |index| self.get(index), self.iter());
+        poll_slot_mut(self, index, id)
+    }
+
+    fn poll_child_mut_hinted(&mut self, id: ID) -> bool {
+        let index = find_slot_near_hint(id, |index| self.get(index));
+        poll_slot_mut(self, index, id)
+    }
+
+    fn contains_child(&self, id: ID) -> bool {
+        self.iter().any(|child| child.contains_child(id))
+    }
+
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn NodeClone)) {
+        for child in self {
+            child.for_each_child(f);
+        }
+    }
+
+    fn take_child(&mut self, id: ID) -> Option<Rc<dyn NodeClone>> {
+        let index = find_slot(id, |index| self.get(index), self.iter())?;
+        self[index].take_child(id).or_else(|| self.remove(index).into_node())
+    }
+
+    fn into_node(self) -> Option<Rc<dyn NodeClone>> {
+        None
+    }
+}
+
+impl<C: ChildContainer + Clone> ChildContainer for im::Vector<C> {
+    fn poll_all(&self) {
+        for (index, child) in self.iter().enumerate() {
+            child.poll_all();
+            child.set_slot_hint(index);
+        }
+    }
+
+    fn poll_all_mut(&mut self) {
+        // Copying shared chunks clones the elements, which must not be seen by Cod.
+        with_context(|c| Context::suspend(c, || self.iter_mut().for_each(drop)));
+        for (index, child) in self.iter_mut().enumerate() {
+            child.poll_all_mut();
+            child.set_slot_hint(index);
+        }
+    }
+
+    /// Only copies the path to the found element, so this is logarithmic in the
+    /// length of the vector if the position of the child is known.
+    fn poll_child_mut(&mut self, id: ID) -> bool {
+        // search first, so that only the path to the found element is copied
+        let index = find_slot(id, |index| self.get(index), self.iter());
+        poll_vector_slot_mut(self, index, id)
+    }
+
+    fn poll_child_mut_hinted(&mut self, id: ID) -> bool {
+        let index = find_slot_near_hint(id, |index| self.get(index));
+        poll_vector_slot_mut(self, index, id)
+    }
+
+    fn contains_child(&self, id: ID) -> bool {
+        self.iter().any(|child| child.contains_child(id))
+    }
+
+    fn for_each_child(&self, f: &mut dyn FnMut(&dyn NodeClone)) {
+        for child in self {
+            child.for_each_child(f);
+        }
+    }
+
+    fn take_child(&mut self, id: ID) -> Option<Rc<dyn NodeClone>> {
+        let index = find_slot(id, |index| self.get(index), self.iter())?;
+        // copying shared chunks clones the elements, which must not be seen by Cod
+        with_context(|c| Context::suspend(c, || {
+            let element = self.get_mut(index).unwrap();
+            element.take_child(id).or_else(|| self.remove(index).into_node())
+        }))
+    }
+
+    fn into_node(self) -> Option<Rc<dyn NodeClone>> {
+        None
+    }
+}
+
+/// Poll the `Child` with the given ID in the element at `index`, if found.
+fn poll_slot_mut<C: ChildContainer>(elements: &mut [C], index: Option<usize>, id: ID) -> bool {
+    match index {
+        Some(index) => {
+            let found = elements[index].poll_child_mut(id);
+            elements[index].set_slot_hint(index);
+            found
+        },
+        None => false,
+    }
+}
+
+/// Like [`poll_slot_mut`], but for `im::Vector`, where only the path to the element is copied.
+fn poll_vector_slot_mut<C: ChildContainer + Clone>(elements: &mut im::Vector<C>, index: Option<usize>, id: ID)
+    -> bool {
+    match index {
+        Some(index) => {
+            let element = with_context(|c| Context::suspend(c, || elements.get_mut(index))).unwrap();
+            let found = element.poll_child_mut(id);
+            element.set_slot_hint(index);
+            found
+        },
+        None => false,
+    }
+}
+
+impl<T: NodeClone> Child<T> {
+    /// A `Child` pointing to `node`, without it being polled as constructed.
+    pub(crate) fn from_inner(node: Rc<T>) -> Self {
+        Child {
+            inner_ref: ManuallyDrop::new(node),
+        }
+    }
+
+    /// Take the node out, without the `Child` being polled as dropped.
+    fn into_inner(self) -> Rc<T> {
+        let mut child = ManuallyDrop::new(self);
+        // SAFETY: the `Child` is not dropped, so `inner_ref` is not used again
+        unsafe { ManuallyDrop::take(&mut child.inner_ref) }
+    }
+}
+
 impl<T: NodeClone> Deref for Child<T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
@@ -210,16 +732,16 @@ impl<T: NodeClone> Deref for Child<T> {
 
 impl<T: NodeClone> Clone for Child<T> {
     // TODO: for user-facing cloning, there should (instead) be a separate deep_clone
-    // method that takes a new parent. similarly, there shold be a helper
-    // for moving Childs to a different parent.
+    // method that takes a new parent. moving Childs to a different parent is done
+    // with `State::move_node`.
     fn clone(&self) -> Self {
         let mut child = Self {
-            inner_ref: Rc::clone(&self.inner_ref),
+            inner_ref: ManuallyDrop::new(Rc::clone(&self.inner_ref)),
         };
-        CONTEXT.with(|c| {
+        with_context(|c| {
             if let Some(new_ref) =
                 Context::poll_mut(c, PollReason::Clone, Rc::clone(&child.inner_ref)) {
-                child.inner_ref = new_ref;
+                *child.inner_ref = new_ref;
             }
         });
         child
@@ -228,17 +750,26 @@ impl<T: NodeClone> Clone for Child<T> {
 
 impl<T: NodeClone> Drop for Child<T> {
     fn drop(&mut self) {
+        // SAFETY: `inner_ref` is not used again
+        let inner_ref = unsafe { ManuallyDrop::take(&mut self.inner_ref) };
         // XXX: This should only create inconsistencies in the newest version of the data,
         // so going to an old state after catching an unwind _should_ be fine.
-        if std::thread::panicking() { return }
-        CONTEXT.with(|c| {
-            Context::poll(c, PollReason::Drop, Rc::clone(&self.inner_ref));
-        });
+        if !std::thread::panicking() {
+            with_context(|c| {
+                Context::poll(c, PollReason::Drop, Rc::clone(&inner_ref));
+            });
+        }
+        // the default drop would recurse for each level of the subtree being freed
+        if Rc::strong_count(&inner_ref) == 1 {
+            drop_iteratively(inner_ref);
+        }
     }
 }
 
 pub struct MakeMutRef<'a, T: NodeClone> {
-    child: &'a mut Child<T>
+    child: &'a mut Child<T>,
+    /// Whether the node got a new allocation in a mutation session, and needs to be registered
+    register: bool,
 }
 
 impl<'a, T: NodeClone> Deref for MakeMutRef<'a, T> {
@@ -260,8 +791,8 @@ impl<'a, T: NodeClone> Drop for MakeMutRef<'a, T> {
     fn drop(&mut self) {
         // XXX: This should only create inconsistencies in the newest version of the data,
         // so going to an old state after catching an unwind _should_ be fine.
-        if std::thread::panicking() { return }
-        CONTEXT.with(|c| {
+        if std::thread::panicking() || !self.register { return }
+        with_context(|c| {
             Context::poll(c, PollReason::MakeMutPost, Rc::clone(&self.child.inner_ref));
         });
     }
@@ -281,6 +812,7 @@ impl<T: NodeClone + Debug> Debug for Child<T> {
 pub struct State<R: NodeClone + Clone> {
     root: Rc<R>,
     id_lookup: im::HashMap<ID, Weak<dyn NodeClone>>,
+    indexes: Indexes,
 }
 
 impl<R: NodeClone + Clone> State<R> {
@@ -288,55 +820,104 @@ impl<R: NodeClone + Clone> State<R> {
     /// they all have to be created during the execution of this closure and on the same
     /// thread.
     pub fn construct<F: FnOnce() -> R>(construct: F) -> Self {
-        CONTEXT.with(|c| {
-            Context::begin_mutate(c);
-        });
+        expect_ok(Self::try_construct(construct))
+    }
+
+    /// Like [`construct`](State::construct), but returns an error instead of panicking.
+    pub fn try_construct<F: FnOnce() -> R>(construct: F) -> Result<Self, CodError> {
+        let session = Session::begin();
         let root = Rc::new(construct());
-        let mut state = Self {
-            root: Rc::clone(&root),
-            id_lookup: im::HashMap::new(),
-        };
-        CONTEXT.with(|c| {
-            state.apply_updates(Context::end_mutate(c));
-        });
-        state.id_lookup.insert(root.header().id, Rc::downgrade(&root) as Weak<dyn NodeClone>);
-        state
+        Self::from_session(&session, root)
     }
 
     /// Due to implementation details, this has to clone the root and all its
     /// children.
     pub fn new(root: &R) -> Self {
-        CONTEXT.with(|c| {
-            Context::begin_mutate(c);
-        });
+        expect_ok(Self::try_new(root))
+    }
+
+    /// Like [`new`](State::new), but returns an error instead of panicking.
+    pub fn try_new(root: &R) -> Result<Self, CodError> {
+        let session = Session::begin();
         // this initiates a deep clone because mutation context is active
         let root = Rc::new(root.clone());
-        let mut state = Self {
-            root: Rc::clone(&root),
-            id_lookup: im::HashMap::new(),
-        };
-        CONTEXT.with(|c| {
-            state.apply_updates(Context::end_mutate(c));
-        });
-        state.id_lookup.insert(root.header().id, Rc::downgrade(&root) as Weak<dyn NodeClone>);
-        state
+        Self::from_session(&session, root)
     }
 
-    pub fn get_mut<'a, T: NodeClone + Clone>(&'a mut self, mut node: Rc<T>) -> MutRef<'a, R, T> {
-        Rc::make_mut(&mut node);
-        CONTEXT.with(|c| {
-            Context::begin_mutate(c);
-        });
-        MutRef {
-            state: self,
-            node
+    /// Ends the mutation session in which `root` was created.
+    fn from_session(session: &Session, root: Rc<R>) -> Result<Self, CodError> {
+        let mut id_lookup = im::HashMap::new();
+        let mut indexes = Indexes::default();
+        apply_updates(&mut id_lookup, &mut indexes, session.end()?);
+        adopt_children(&*root, |_| ());
+        id_lookup.insert(root.header().id, Rc::downgrade(&root) as Weak<dyn NodeClone>);
+        Ok(Self {
+            root,
+            id_lookup,
+            indexes,
+        })
+    }
+
+    /// Start editing `node`. The edit is committed when the returned `MutRef` is
+    /// dropped, or with [`MutRef::commit`]. If it is never dereferenced mutably,
+    /// the state is left as it is.
+    pub fn get_mut<T: NodeClone + Clone>(&mut self, node: Rc<T>) -> MutRef<'_, R, T> {
+        expect_ok(self.try_get_mut(node))
+    }
+
+    /// Like [`get_mut`](State::get_mut), but returns an error instead of panicking.
+    pub fn try_get_mut<T: NodeClone + Clone>(&mut self, mut node: Rc<T>) -> Result<MutRef<'_, R, T>, CodError> {
+        let id = node.header().id;
+        if !self.id_lookup.contains_key(&id) {
+            return Err(CodError::NotInState(id))
         }
+        // the copy is not part of the session, its children are shared
+        with_context(|c| Context::suspend(c, || { Rc::make_mut(&mut node); }));
+        Ok(MutRef {
+            state: self,
+            node,
+            session: Session::begin(),
+            committed: false,
+            touched: false,
+        })
+    }
+
+    /// Like [`get_mut`](State::get_mut), but finds the node by its ID. Returns `None`
+    /// if there is no such node, or if it is of another type.
+    pub fn get_mut_by_id<T: NodeClone + Clone>(&mut self, id: ID) -> Option<MutRef<'_, R, T>> {
+        let node = self.get(id)?;
+        Some(self.get_mut(node))
+    }
+
+    /// Mutate the node with the given ID in a closure, and commit the change.
+    pub fn update<T: NodeClone + Clone, O>(&mut self, id: ID, update: impl FnOnce(&mut T) -> O)
+        -> Result<O, LookupError> {
+        let node = self.try_get(id)?;
+        let mut node = self.get_mut(node);
+        Ok(update(&mut node))
     }
 
     pub fn ref_from_id(&self, id: ID) -> Option<Rc<dyn NodeClone>> {
         Weak::upgrade(self.id_lookup.get(&id)?)
     }
 
+    /// Find a node of type `T` by its ID. Returns `None` if there is no such node,
+    /// or if it is of another type. See [`try_get`](State::try_get) to tell these apart.
+    pub fn get<T: NodeClone>(&self, id: ID) -> Option<Rc<T>> {
+        self.try_get(id).ok()
+    }
+
+    /// Like [`get`](State::get), but tells why the node could not be found.
+    pub fn try_get<T: NodeClone>(&self, id: ID) -> Result<Rc<T>, LookupError> {
+        let node = self.ref_from_id(id).ok_or(LookupError::NotFound(id))?;
+        let found = node.type_name();
+        downcast_rc(node).ok_or(LookupError::WrongType {
+            id,
+            expected: std::any::type_name::<T>(),
+            found,
+        })
+    }
+
     pub fn root(&self) -> &R {
         &self.root
     }
@@ -345,34 +926,149 @@ impl<R: NodeClone + Clone> State<R> {
         Rc::clone(&self.root)
     }
 
-    fn apply_updates(&mut self, updates: impl Iterator<Item=IDMapUpdate>) {
-        for update in updates {
-            match update {
-                IDMapUpdate::Set(id, new_ref) => {
-                    self.id_lookup.insert(id, new_ref);
-                },
-                IDMapUpdate::Erase(id) => {
-                    self.id_lookup.remove(&id);
-                },
-            }
+}
+
+/// For the methods that panic instead of returning a [`CodError`].
+pub(crate) fn expect_ok<O>(result: Result<O, CodError>) -> O {
+    result.unwrap_or_else(|error| panic!("Cod: {}", error))
+}
+
+/// Optional secondary indexes of a [`State`], kept in sync with its ID lookup.
+#[derive(Clone, Default)]
+pub(crate) struct Indexes {
+    types: Option<TypeIndex>,
+    backlinks: Option<Backlinks>,
+}
+
+/// IDs of the nodes that got a new version in a mutation session, including new nodes.
+pub(crate) fn new_versions(updates: &[IDMapUpdate]) -> Vec<ID> {
+    updates.iter()
+        .filter_map(|update| match update {
+            IDMapUpdate::Set(id, ..) => Some(*id),
+            IDMapUpdate::Erase(_) => None,
+        })
+        .collect()
+}
+
+/// Apply the updates of a mutation session to an ID lookup, and to its indexes.
+pub(crate) fn apply_updates(
+    id_lookup: &mut im::HashMap<ID, Weak<dyn NodeClone>>,
+    indexes: &mut Indexes,
+    updates: impl Iterator<Item=IDMapUpdate>,
+) {
+    for update in updates {
+        match update {
+            IDMapUpdate::Set(id, new_ref, type_id) => {
+                if let Some(node) = Weak::upgrade(&new_ref) {
+                    adopt_children(&*node, |_| ());
+                    if let Some(backlinks) = &mut indexes.backlinks {
+                        backlinks.update(id, &*node);
+                    }
+                }
+                let existed = id_lookup.insert(id, new_ref).is_some();
+                if let (false, Some(types)) = (existed, &mut indexes.types) {
+                    types.insert(id, type_id);
+                }
+            },
+            IDMapUpdate::Erase(id) => {
+                if id_lookup.remove(&id).is_none() {
+                    continue
+                }
+                if let Some(types) = &mut indexes.types {
+                    types.remove(id);
+                }
+                if let Some(backlinks) = &mut indexes.backlinks {
+                    backlinks.remove(id);
+                }
+            },
         }
     }
 }
 
+/// Set the parent ID of the children of `node` that were created with [`Child::new`].
+/// `visit` is called with the ID of each child afterwards.
+pub(crate) fn adopt_children(node: &dyn NodeClone, mut visit: impl FnMut(ID)) {
+    let parent_id = node.header().id;
+    let mut adopt = |child: &dyn NodeClone| {
+        let header = child.header();
+        if header.parent_id().is_none() {
+            header.set_parent_id(Some(parent_id));
+        }
+        visit(header.id);
+    };
+    if node.implements_poll_all() {
+        node.for_each_child(&mut adopt);
+    } else {
+        for child in with_context(|c| Context::children(c, node)) {
+            adopt(&*child);
+        }
+    }
+}
+
+/// Mutable reference to a node of a [`State`], see [`State::get_mut`].
+///
+/// Has a mutation session of its own, so several `MutRef`s to different states may
+/// be alive at once. Changes to `Child`ren, including clones, are attributed to the
+/// `MutRef` that was most recently dereferenced. Committing fails with
+/// [`CodError::WrongSession`] if they were made to the node of another one.
 pub struct MutRef<'a, R: NodeClone + Clone, T: NodeClone> {
     state: &'a mut State<R>,
     node: Rc<T>,
+    session: Session,
+    committed: bool,
+    /// Whether the node was dereferenced mutably
+    touched: bool,
+}
+
+impl<'a, R: NodeClone + Clone, T: NodeClone> MutRef<'a, R, T> {
+    /// Commit the edit now. Equivalent to dropping the `MutRef`.
+    pub fn commit(self) {
+        expect_ok(self.try_commit())
+    }
+
+    /// Like [`commit`](MutRef::commit), but returns an error instead of panicking.
+    /// The state is left unchanged in that case.
+    pub fn try_commit(mut self) -> Result<(), CodError> {
+        self.committed = true;
+        self.state.commit_edit(&self.session, Rc::clone(&self.node) as Rc<dyn NodeClone>, self.touched)
+    }
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// Ends the mutation session of a `MutRef`, and commits the edited `node`. If it was
+    /// not `touched` and nothing happened in the session, the state is left as it is.
+    fn commit_edit(&mut self, session: &Session, node: Rc<dyn NodeClone>, touched: bool) -> Result<(), CodError> {
+        let updates: Vec<_> = session.end()?.collect();
+        if !touched && updates.is_empty() {
+            return Ok(())
+        }
+        let mut id_lookup = self.id_lookup.clone();
+        let mut indexes = self.indexes.clone();
+        let new_versions = new_versions(&updates);
+        apply_updates(&mut id_lookup, &mut indexes, updates.into_iter());
+        let id = node.header().id;
+        if !id_lookup.contains_key(&id) {
+            return Err(CodError::NotInState(id))
+        }
+        let mut edited = HashMap::new();
+        edited.insert(id, node);
+        self.propagate(id_lookup, indexes, edited, new_versions)
+    }
 }
 
 impl<'a, R: NodeClone + Clone, T: NodeClone> Deref for MutRef<'a, R, T> {
     type Target = T;
     fn deref(&self) -> &Self::Target {
+        // cloning a `Child` read through here is part of this session
+        self.session.activate();
         &self.node
     }
 }
 
 impl<'a, R: NodeClone + Clone, T: NodeClone> DerefMut for MutRef<'a, R, T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        self.session.activate();
+        self.touched = true;
         // Will not panic because the node Rc is mutably borrowed and
         // made unique upon creation of self.
         Rc::get_mut(&mut self.node).unwrap()
@@ -381,32 +1077,12 @@ impl<'a, R: NodeClone + Clone, T: NodeClone> DerefMut for MutRef<'a, R, T> {
 
 impl<'a, R: NodeClone + Clone, T: NodeClone> Drop for MutRef<'a, R, T> {
     fn drop(&mut self) {
-        // XXX: This should only create inconsistencies in the newest version of the data,
-        // so going to an old state after catching an unwind _should_ be fine.
-        if std::thread::panicking() { return }
-        CONTEXT.with(|c| {
-            self.state.apply_updates(Context::end_mutate(c));
-        });
-        self.state.id_lookup.insert(self.node.header().id, Rc::downgrade(&self.node) as Weak<dyn NodeClone>);
-        let mut prev_node = Rc::clone(&self.node) as Rc<dyn NodeClone>;
-        while let Some(parent_id) = prev_node.header().parent_id {
-            let parent = Weak::upgrade(self.state.id_lookup.get(&parent_id).unwrap()).unwrap();
-            CONTEXT.with(|c| {
-                Context::set_replacement(c,
-                    Replacement { id: prev_node.header().id, replace_with: Rc::clone(&prev_node) as Rc<dyn NodeClone> }
-                );
-            });
-            prev_node = parent.dyn_clone();
-            CONTEXT.with(|c| {
-                if !Context::finish_replacement(c) {
-                    panic!("Cod: Could not find associated `Child` while traversing up")
-                }
-            });
+        // When unwinding, the state has not been touched yet, so it stays consistent.
+        // The session is aborted, so that mutation can continue after catching the unwind.
+        if !self.committed && !std::thread::panicking() {
+            expect_ok(self.state.commit_edit(&self.session, Rc::clone(&self.node) as Rc<dyn NodeClone>, self.touched));
         }
-        CONTEXT.with(|c| {
-            self.state.apply_updates(Context::end_replacement(c));
-        });
-        self.state.root = downcast_rc(prev_node).unwrap();
+        // the working copy, if not committed, is dropped without being noticed
+        self.session.abort();
     }
 }
-
@@ -0,0 +1,77 @@
+//! Walking upwards from a node, using the ID lookup of a state.
+
+use crate::{NodeClone, State, ID, Weak};
+
+/// Iterator over the ancestors of a node, see [`State::ancestors`].
+pub struct Ancestors<'a, R: NodeClone + Clone> {
+    state: &'a State<R>,
+    next: Option<ID>,
+}
+
+impl<'a, R: NodeClone + Clone> Iterator for Ancestors<'a, R> {
+    type Item = ID;
+
+    fn next(&mut self) -> Option<ID> {
+        let id = self.next?;
+        self.next = self.state.parent_of(id);
+        Some(id)
+    }
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// The ID of the parent of the node with the given ID. `None` for the root,
+    /// and for IDs not in the state.
+    pub fn parent_of(&self, id: ID) -> Option<ID> {
+        Weak::upgrade(self.id_lookup.get(&id)?)?.header().parent_id()
+    }
+
+    /// The IDs of the parent of the node with the given ID, its parent and so on,
+    /// ending with the root. Empty for the root, and for IDs not in the state.
+    pub fn ancestors(&self, id: ID) -> Ancestors<'_, R> {
+        Ancestors {
+            state: self,
+            next: self.parent_of(id),
+        }
+    }
+
+    /// The number of ancestors of the node with the given ID, so zero for the root.
+    /// `None` for IDs not in the state.
+    pub fn depth(&self, id: ID) -> Option<usize> {
+        if !self.id_lookup.contains_key(&id) {
+            return None
+        }
+        Some(self.ancestors(id).count())
+    }
+
+    /// The IDs from the root down to the node with the given ID, both included.
+    /// Empty for IDs not in the state.
+    pub fn path_from_root(&self, id: ID) -> Vec<ID> {
+        if !self.id_lookup.contains_key(&id) {
+            return Vec::new()
+        }
+        let mut path: Vec<ID> = std::iter::once(id).chain(self.ancestors(id)).collect();
+        path.reverse();
+        path
+    }
+
+    /// The deepest node that has both of the given nodes in its subtree. This is one
+    /// of the nodes itself if it is an ancestor of the other. `None` if either of the
+    /// IDs is not in the state.
+    pub fn lowest_common_ancestor(&self, a: ID, b: ID) -> Option<ID> {
+        let (mut a, mut b) = (a, b);
+        let (mut depth_a, mut depth_b) = (self.depth(a)?, self.depth(b)?);
+        while depth_a > depth_b {
+            a = self.parent_of(a)?;
+            depth_a -= 1;
+        }
+        while depth_b > depth_a {
+            b = self.parent_of(b)?;
+            depth_b -= 1;
+        }
+        while a != b {
+            a = self.parent_of(a)?;
+            b = self.parent_of(b)?;
+        }
+        Some(a)
+    }
+}
@@ -0,0 +1,135 @@
+//! Non-owning references between nodes.
+
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use crate::{NodeClone, State, ID, Rc, LookupError};
+use crate::context::{with_context, Context};
+
+/// A reference to a node of type `T` by its ID, for relations other than
+/// ownership, like an arrow pointing at a shape. It may also be null.
+///
+/// Unlike a `Child`, a `NodeRef` does not keep the node alive and is not part of
+/// the tree structure, so it is skipped when copying and removing subtrees. It is
+/// resolved against a [`State`], and may be dangling if the node was removed,
+/// unless the state keeps track of references (see [`State::with_backlinks`]),
+/// in which case its [`RefPolicy`] applies.
+///
+/// See [`Child::clone_remapping_refs`](crate::Child::clone_remapping_refs) for
+/// copying a subtree along with the references inside it.
+pub struct NodeRef<T> {
+    target: Option<ID>,
+    policy: RefPolicy,
+    // does not own a T, and is Send and Sync regardless of T
+    marker: PhantomData<fn() -> T>,
+}
+
+/// What happens to a [`NodeRef`] when the node it points to is removed, in a state
+/// that keeps track of references (see [`State::with_backlinks`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RefPolicy {
+    /// The reference becomes null.
+    #[default]
+    Nullify,
+    /// The node containing the reference is removed as well.
+    Cascade,
+    /// The edit removing the node fails with [`CodError::StillReferenced`](crate::CodError::StillReferenced).
+    Reject,
+}
+
+impl<T: NodeClone> NodeRef<T> {
+    /// A reference to the node with the given ID. Whether there is such a node,
+    /// and whether it is of type `T`, is only checked when resolving.
+    pub fn new(id: ID) -> Self {
+        NodeRef {
+            target: Some(id),
+            policy: RefPolicy::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// A reference to `node`.
+    pub fn to(node: &T) -> Self {
+        Self::new(node.header().id)
+    }
+
+    /// A reference to nothing. This is also the `Default`.
+    pub fn null() -> Self {
+        NodeRef {
+            target: None,
+            policy: RefPolicy::default(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Set what happens to the reference when its target is removed.
+    pub fn on_remove(mut self, policy: RefPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The ID of the target, `None` if the reference is null.
+    pub fn id(&self) -> Option<ID> {
+        self.target
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.target.is_none()
+    }
+
+    pub fn policy(&self) -> RefPolicy {
+        self.policy
+    }
+
+    /// Find the target in `state`. Returns `None` if the reference is null, if there
+    /// is no such node, or if it is of another type.
+    pub fn resolve<R: NodeClone + Clone>(&self, state: &State<R>) -> Option<Rc<T>> {
+        state.get(self.target?)
+    }
+
+    /// Like [`resolve`](NodeRef::resolve), but tells why the target could not be found.
+    /// Returns `Ok(None)` if the reference is null.
+    pub fn try_resolve<R: NodeClone + Clone>(&self, state: &State<R>) -> Result<Option<Rc<T>>, LookupError> {
+        self.target.map(|id| state.try_get(id)).transpose()
+    }
+}
+
+impl<T: NodeClone> Default for NodeRef<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> Clone for NodeRef<T> {
+    /// Keeps pointing to the same node, unless that node is being copied in
+    /// [`Child::clone_remapping_refs`](crate::Child::clone_remapping_refs), or
+    /// the reference is being nullified.
+    fn clone(&self) -> Self {
+        NodeRef {
+            target: with_context(|c| Context::cloned_ref(c, self.target, self.policy)),
+            policy: self.policy,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> PartialEq for NodeRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.target == other.target
+    }
+}
+
+impl<T> Eq for NodeRef<T> {}
+
+impl<T> Hash for NodeRef<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.target.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for NodeRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NodeRef<{}>({:?})", std::any::type_name::<T>(), self.target)
+    }
+}
@@ -0,0 +1,118 @@
+//! Serde support, enabled with the `serde` feature.
+//!
+//! A `Child` is serialized as the node it points to, and a `Header` only
+//! stores the ID. A `NodeRef` stores the ID of its target and its policy.
+//! Parent IDs are not stored, they are restored when loading a `State`.
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use std::mem::ManuallyDrop;
+use crate::{Header, Child, NodeRef, RefPolicy, State, NodeClone, ID, Rc, CodError, im};
+use crate::context::{with_context, Context, Session, PollReason, IDMapUpdate};
+use crate::id::reserve_id;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Header")]
+struct HeaderRepr {
+    id: ID,
+}
+
+impl Serialize for Header {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        HeaderRepr { id: self.id }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Header {
+    /// New IDs created after this will not collide with the loaded one.
+    /// Fails for `ID::MAX`, which is never handed out.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let HeaderRepr { id } = HeaderRepr::deserialize(deserializer)?;
+        if !reserve_id(id) {
+            return Err(D::Error::custom(format_args!("Cod: ID {} is out of range", id)));
+        }
+        Ok(Header {
+            id,
+            parent_id: Default::default(),
+            slot: Default::default(),
+        })
+    }
+}
+
+impl<T: NodeClone + Serialize> Serialize for Child<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (*self.inner_ref).serialize(serializer)
+    }
+}
+
+impl<'de, T: NodeClone + Deserialize<'de>> Deserialize<'de> for Child<T> {
+    /// The parent ID of the loaded node is not set. Loading a whole `State` takes care of
+    /// that, otherwise use [`Child::set_parent`].
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Child {
+            inner_ref: ManuallyDrop::new(Rc::new(T::deserialize(deserializer)?)),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "NodeRef")]
+struct NodeRefRepr {
+    target: Option<ID>,
+    policy: RefPolicy,
+}
+
+impl<T: NodeClone> Serialize for NodeRef<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NodeRefRepr { target: self.id(), policy: self.policy() }.serialize(serializer)
+    }
+}
+
+impl<'de, T: NodeClone> Deserialize<'de> for NodeRef<T> {
+    /// Loaded nodes keep their IDs, so the reference stays valid.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let NodeRefRepr { target, policy } = NodeRefRepr::deserialize(deserializer)?;
+        let node_ref = match target {
+            Some(id) => NodeRef::new(id),
+            None => NodeRef::null(),
+        };
+        Ok(node_ref.on_remove(policy))
+    }
+}
+
+impl<R: NodeClone + Clone + Serialize> Serialize for State<R> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.root.serialize(serializer)
+    }
+}
+
+impl<'de, R: NodeClone + Clone + Deserialize<'de>> Deserialize<'de> for State<R> {
+    /// Rebuilds the ID lookup and parent IDs of the loaded tree.
+    /// Fails if the same ID appears more than once.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let loaded = Rc::new(R::deserialize(deserializer)?);
+        let session = Session::begin();
+        // copies the tree once, keeping IDs but linking up parents
+        let root = with_context(|c| {
+            Context::poll_mut(c, PollReason::Relink(None), Rc::clone(&loaded))
+        }).unwrap();
+        let updates = session.end();
+        // drop the unlinked version outside the mutation session
+        drop(loaded);
+        drop(session);
+        let updates = updates.map_err(D::Error::custom)?;
+        let mut id_lookup = im::HashMap::new();
+        for update in updates {
+            if let IDMapUpdate::Set(id, node, _) = update {
+                if id_lookup.insert(id, node).is_some() {
+                    return Err(D::Error::custom(CodError::DuplicateID(id)));
+                }
+            }
+        }
+        Ok(State {
+            root,
+            id_lookup,
+            indexes: Default::default(),
+        })
+    }
+}
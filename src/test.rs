@@ -1,7 +1,8 @@
 
-use crate::{Header, Node, Child, State};
+use crate::{Header, Node, Child, ChildContainer, NodeRef, State, History, UndoTree, LookupError, CodError, EditError, Diff};
 
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 struct TestNode {
     header: Header,
     data: i32,
@@ -21,7 +22,7 @@ impl TestNode {
     }
 }
 
-// can't use the derive inside the crate
+// implemented manually to exercise the fallback paths
 impl Node for TestNode {
     fn header(&self) -> &Header { &self.header }
     fn header_mut(&mut self) -> &mut Header { &mut self.header }
@@ -41,3 +42,1251 @@ fn deep_copy() {
     // deep copy should change ids
     assert_ne!(state2.root().child.as_ref().unwrap().header.id, state2.root().second_child.as_ref().unwrap().header.id);
 }
+
+#[test]
+fn history_undo_redo() {
+    let mut history = History::new(State::new(&TestNode::new(1, Some(TestNode::new(2, None)))));
+    assert!(!history.can_undo());
+    history.get_mut(history.root_ref()).data = 10;
+    history.get_mut(history.root().child.as_ref().unwrap().get_ref()).data = 20;
+    assert_eq!(history.root().data, 10);
+    assert_eq!(history.root().child.as_ref().unwrap().data, 20);
+    assert!(history.undo());
+    assert_eq!(history.root().child.as_ref().unwrap().data, 2);
+    assert!(history.undo());
+    assert_eq!(history.root().data, 1);
+    assert!(!history.undo());
+    assert!(history.redo());
+    assert_eq!(history.root().data, 10);
+    assert_eq!(history.root().child.as_ref().unwrap().data, 2);
+    // a new edit discards the redo steps
+    history.get_mut(history.root_ref()).data = 11;
+    assert!(!history.can_redo());
+
+    // sessions that change nothing are not undo steps
+    let mut history = History::new(State::new(&TestNode::new(1, None)));
+    let _ = history.get_mut(history.root_ref());
+    assert!(!history.can_undo());
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut root = history.get_mut(history.root_ref());
+        root.data = 2;
+        panic!("validation failed");
+    }));
+    assert!(panicked.is_err());
+    assert!(!history.can_undo());
+    history.edit(|state| state.get_mut(state.root_ref()).data = 3);
+    assert!(history.can_undo());
+    history.edit(|_| ());
+    assert!(history.undo());
+    assert_eq!(history.root().data, 1);
+    assert!(!history.can_undo());
+}
+
+#[test]
+fn history_group_and_depth() {
+    let mut history = History::new(State::new(&TestNode::new(0, None)));
+    history.group(|history| {
+        for i in 1..=3 {
+            history.get_mut(history.root_ref()).data = i;
+        }
+    });
+    assert_eq!(history.root().data, 3);
+    assert!(history.undo());
+    assert_eq!(history.root().data, 0);
+    assert!(!history.can_undo());
+    // starting with a session that changes nothing
+    history.group(|history| {
+        let _ = history.get_mut(history.root_ref());
+        history.get_mut(history.root_ref()).data = 4;
+    });
+    assert!(history.undo());
+    assert_eq!(history.root().data, 0);
+    assert!(!history.can_undo());
+
+    history.set_max_depth(Some(2));
+    for i in 1..=5 {
+        history.get_mut(history.root_ref()).data = i;
+    }
+    assert!(history.undo());
+    assert!(history.undo());
+    assert!(!history.undo());
+    assert_eq!(history.root().data, 3);
+}
+
+#[test]
+fn undo_tree_branches() {
+    let mut tree = UndoTree::new(State::new(&TestNode::new(0, None)));
+    tree.edit("Set to 1", |state| state.get_mut(state.root_ref()).data = 1);
+    let one = tree.current();
+    tree.edit("Set to 2", |state| state.get_mut(state.root_ref()).data = 2);
+    let two = tree.current();
+    assert!(tree.undo());
+    assert_eq!(tree.root().data, 1);
+    // mutating after undo starts a new branch
+    tree.get_mut(tree.root_ref()).data = 3;
+    let three = tree.current();
+    assert!(tree.can_undo() && !tree.redo());
+    assert_eq!(tree.checkpoint(one).unwrap().children(), &[two, three]);
+    assert_eq!(tree.checkpoint(two).unwrap().label(), Some("Set to 2"));
+    assert_eq!(tree.checkpoint(three).unwrap().label(), None);
+    // the old branch is still reachable
+    assert!(tree.jump_to(two));
+    assert_eq!(tree.root().data, 2);
+    assert!(tree.undo());
+    assert!(tree.redo());
+    assert_eq!(tree.current(), two);
+    assert!(tree.undo());
+    assert!(tree.undo());
+    assert!(!tree.undo());
+    assert_eq!(tree.root().data, 0);
+    assert_eq!(tree.iter().count(), 4);
+
+    // sessions that leave the state unchanged create no checkpoint
+    assert!(tree.redo());
+    assert_eq!(tree.current(), one);
+    drop(tree.get_mut(tree.root_ref()));
+    tree.edit("Nothing", |state| { let _ = state.get_mut(state.root_ref()).data; });
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        tree.edit("Panic", |state| {
+            let mut root = state.get_mut(state.root_ref());
+            root.data = 4;
+            panic!();
+        })
+    }));
+    assert!(panicked.is_err());
+    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut root = tree.get_mut(tree.root_ref());
+        root.data = 5;
+        panic!();
+    }));
+    assert_eq!(tree.current(), one);
+    assert!(tree.redo());
+    assert_eq!(tree.current(), two);
+    assert_eq!(tree.checkpoint(one).unwrap().children(), &[two, three]);
+    assert_eq!(tree.iter().count(), 4);
+}
+
+#[test]
+fn diff_states() {
+    let state1 = State::new(&TestNode::new(1, Some(TestNode::new(2, Some(TestNode::new(3, None))))));
+    let root_id = state1.root().header.id;
+    let middle_id = state1.root().child.as_ref().unwrap().header.id;
+    let leaf_id = state1.root().child.as_ref().unwrap().child.as_ref().unwrap().header.id;
+    assert!(State::diff(&state1, &state1.clone()).is_empty());
+
+    let mut state2 = state1.clone();
+    state2.get_mut(state2.root().child.as_ref().unwrap().get_ref()).data = 20;
+    let diff = State::diff(&state1, &state2);
+    let mut modified = vec![root_id, middle_id];
+    modified.sort_unstable();
+    assert_eq!(diff.modified, modified);
+    assert!(diff.added.is_empty() && diff.removed.is_empty());
+
+    let mut state3 = state2.clone();
+    {
+        let mut root = state3.get_mut(state3.root_ref());
+        root.second_child = Some(Child::with_parent(root.header.id, TestNode::new(4, None)));
+    }
+    state3.get_mut(state3.root().child.as_ref().unwrap().get_ref()).child = None;
+    let diff = State::diff(&state2, &state3);
+    assert_eq!(diff.added, vec![state3.root().second_child.as_ref().unwrap().header.id]);
+    assert_eq!(diff.removed, vec![leaf_id]);
+    assert_eq!(diff.modified, modified);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_roundtrip() {
+    let state1 = State::new(&TestNode::new(1, Some(TestNode::new(2, Some(TestNode::new(3, None))))));
+    let json = serde_json::to_string(&state1).unwrap();
+    let mut state2: State<TestNode> = serde_json::from_str(&json).unwrap();
+    let leaf = state2.root().child.as_ref().unwrap().child.as_ref().unwrap().get_ref();
+    assert_eq!(leaf.header.id, state1.root().child.as_ref().unwrap().child.as_ref().unwrap().header.id);
+    assert!(state2.ref_from_id(leaf.header.id).is_some());
+    // mutation requires the parent links to be restored
+    state2.get_mut(leaf).data = 30;
+    assert_eq!(state2.root().child.as_ref().unwrap().child.as_ref().unwrap().data, 30);
+    assert_eq!(state1.root().child.as_ref().unwrap().child.as_ref().unwrap().data, 3);
+    // loaded IDs are never handed out again
+    let loaded_max = serde_json::from_str::<State<TestNode>>(
+        r#"{"header":{"id":1000000},"data":0,"child":null,"second_child":null}"#
+    ).unwrap().root().header.id;
+    assert!(Header::new().id > loaded_max);
+    // duplicate IDs are rejected
+    let duplicate = r#"{"header":{"id":5},"data":0,"child":{"header":{"id":5},"data":1,"child":null,"second_child":null},"second_child":null}"#;
+    assert!(serde_json::from_str::<State<TestNode>>(duplicate).is_err());
+    let out_of_range = r#"{"header":{"id":18446744073709551615},"data":0,"child":null,"second_child":null}"#;
+    assert!(serde_json::from_str::<State<TestNode>>(out_of_range).is_err());
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn send_state_to_thread() {
+    let state = State::new(&TestNode::new(1, Some(TestNode::new(2, None))));
+    let snapshot = state.clone();
+    let sum = std::thread::spawn(move || {
+        snapshot.root().data + snapshot.root().child.as_ref().unwrap().data
+    }).join().unwrap();
+    assert_eq!(sum, 3);
+    assert_eq!(state.root().data, 1);
+}
+
+#[test]
+fn repeated_mutation() {
+    let mut state = State::new(&TestNode::new(1, Some(TestNode::new(2, Some(TestNode::new(3, None))))));
+    for i in 0..3 {
+        let leaf = state.root().child.as_ref().unwrap().child.as_ref().unwrap().get_ref();
+        state.get_mut(leaf).data = i;
+    }
+    assert_eq!(state.root().child.as_ref().unwrap().child.as_ref().unwrap().data, 2);
+}
+
+#[derive(Clone, crate::Node)]
+struct ListNode {
+    header: Header,
+    data: i32,
+    list: Vec<Child<ListNode>>,
+    vector: crate::im::Vector<Child<ListNode>>,
+    optional: Option<Child<TestNode>>,
+}
+
+impl ListNode {
+    fn new(data: i32) -> ListNode {
+        ListNode {
+            header: Header::new(),
+            data,
+            list: Vec::new(),
+            vector: crate::im::Vector::new(),
+            optional: None,
+        }
+    }
+}
+
+#[test]
+fn derived_polling() {
+    let state1 = State::construct(|| {
+        let mut root = ListNode::new(0);
+        for i in 1..=3 {
+            root.list.push(Child::with_parent(&root.header, ListNode::new(i)));
+            root.vector.push_back(Child::with_parent(&root.header, ListNode::new(10 + i)));
+        }
+        root.optional = Some(Child::with_parent(&root.header, TestNode::new(100, None)));
+        root
+    });
+    assert!(state1.root().implements_poll_all());
+    // propagation through poll_child_mut
+    let mut state2 = state1.clone();
+    state2.get_mut(state2.root().vector[1].get_ref()).data = 42;
+    state2.get_mut(state2.root().list[2].get_ref()).data = 43;
+    assert_eq!(state2.root().vector[1].data, 42);
+    assert_eq!(state2.root().list[2].data, 43);
+    assert_eq!(state1.root().vector[1].data, 12);
+    // deep copy through poll_all_mut
+    let copied_id = state2.root().vector[0].get_id();
+    {
+        let mut root = state2.get_mut(state2.root_ref());
+        let copy = root.vector[0].clone();
+        root.list.push(copy);
+    }
+    let copy = state2.root().list.last().unwrap();
+    assert_ne!(copy.get_id(), copied_id);
+    assert!(state2.ref_from_id(copy.get_id()).is_some());
+    // removal through poll_all
+    let removed_id = state2.root().list[0].get_id();
+    state2.get_mut(state2.root_ref()).list.remove(0);
+    assert!(state2.ref_from_id(removed_id).is_none());
+    assert!(state1.ref_from_id(removed_id).is_some());
+}
+
+#[derive(Clone, crate::Node)]
+enum Shape {
+    Rect { header: Header, width: i32 },
+    Group(Header, Vec<Child<Shape>>),
+    Wrapped(ListNode),
+}
+
+#[test]
+fn derived_enum() {
+    let state1 = State::construct(|| {
+        let header = Header::new();
+        let mut wrapped = ListNode::new(5);
+        wrapped.list.push(Child::with_parent(&wrapped.header, ListNode::new(6)));
+        let children = vec![
+            Child::with_parent(&header, Shape::Rect { header: Header::new(), width: 1 }),
+            Child::with_parent(&header, Shape::Wrapped(wrapped)),
+        ];
+        Shape::Group(header, children)
+    });
+    let root = state1.root();
+    assert!(root.implements_poll_all());
+    let children = match root {
+        Shape::Group(_, children) => children,
+        _ => unreachable!(),
+    };
+    assert_eq!(children[0].header().parent_id(), Some(root.header().id));
+    assert!(matches!(&*children[0], Shape::Rect { width: 1, .. }));
+    let wrapped_id = children[1].get_id();
+    let inner = match &*children[1] {
+        Shape::Wrapped(inner) => inner.list[0].get_ref(),
+        _ => unreachable!(),
+    };
+    assert_eq!(inner.header.parent_id(), Some(wrapped_id));
+    // propagates through both the group and the wrapped node
+    let mut state2 = state1.clone();
+    state2.get_mut(inner).data = 60;
+    match state2.root() {
+        Shape::Group(_, children) => match &*children[1] {
+            Shape::Wrapped(inner) => assert_eq!(inner.list[0].data, 60),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+    let rect_id = children[0].get_id();
+    if let Shape::Group(_, children) = &mut *state2.get_mut(state2.root_ref()) {
+        children.remove(0);
+    }
+    assert!(state2.ref_from_id(rect_id).is_none());
+}
+
+#[derive(Clone, crate::Node)]
+struct Labeled<T: Clone + crate::MaybeSync + 'static>(#[cod(skip)] T, #[cod(header)] Header, Option<Child<Labeled<T>>>);
+
+#[test]
+fn derived_generic_tuple() {
+    let state1 = State::construct(|| {
+        let header = Header::new();
+        let child = Child::with_parent(&header, Labeled("child", Header::new(), None));
+        Labeled("root", header, Some(child))
+    });
+    assert!(state1.root().implements_poll_child());
+    let child = state1.root().2.as_ref().unwrap().get_ref();
+    assert_eq!(child.header().parent_id(), Some(state1.root().1.id));
+    let mut state2 = state1.clone();
+    state2.get_mut(child).0 = "changed";
+    assert_eq!(state2.root().2.as_ref().unwrap().0, "changed");
+    assert_eq!(state1.root().2.as_ref().unwrap().0, "child");
+}
+
+type Kids = Vec<Child<Hidden>>;
+
+#[derive(Clone)]
+struct Bundle {
+    items: Vec<Child<Hidden>>,
+}
+
+/// Has `Child`ren behind a type alias and inside another struct, which the derive cannot see
+#[derive(Clone, crate::Node)]
+struct Hidden {
+    header: Header,
+    data: i32,
+    aliased: Kids,
+    bundled: Bundle,
+}
+
+impl Hidden {
+    fn new(data: i32) -> Hidden {
+        Hidden {
+            header: Header::new(),
+            data,
+            aliased: Vec::new(),
+            bundled: Bundle { items: Vec::new() },
+        }
+    }
+}
+
+#[test]
+fn derived_hidden_children() {
+    let state1 = State::construct(|| {
+        let mut root = Hidden::new(0);
+        root.aliased.push(Child::new(Hidden::new(1)));
+        root.bundled.items.push(Child::new(Hidden::new(2)));
+        root
+    });
+    // falls back to `Clone`
+    assert!(!state1.root().implements_poll_all() && !state1.root().implements_poll_child());
+    let mut state2 = state1.clone();
+    state2.get_mut(state1.root().aliased[0].get_ref()).data = 10;
+    state2.get_mut(state1.root().bundled.items[0].get_ref()).data = 20;
+    assert_eq!((state2.root().aliased[0].data, state2.root().bundled.items[0].data), (10, 20));
+    assert_eq!((state1.root().aliased[0].data, state1.root().bundled.items[0].data), (1, 2));
+    let copy = State::new(state1.root());
+    assert_ne!(copy.root().aliased[0].get_id(), state1.root().aliased[0].get_id());
+    assert_ne!(copy.root().bundled.items[0].get_id(), state1.root().bundled.items[0].get_id());
+}
+
+#[test]
+fn typed_lookup() {
+    let state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        root.optional = Some(Child::with_parent(&root.header, TestNode::new(1, None)));
+        root
+    });
+    let id = state.root().optional.as_ref().unwrap().get_id();
+    assert_eq!(state.get::<TestNode>(id).unwrap().data, 1);
+    assert!(state.get::<ListNode>(id).is_none());
+    assert!(matches!(state.try_get::<ListNode>(id), Err(LookupError::WrongType { .. })));
+    let missing = Header::new().id;
+    assert_eq!(state.try_get::<TestNode>(missing).err(), Some(LookupError::NotFound(missing)));
+}
+
+#[test]
+fn mutate_by_id() {
+    let state1 = State::new(&TestNode::new(1, Some(TestNode::new(2, None))));
+    let id = state1.root().child.as_ref().unwrap().get_id();
+    let mut state2 = state1.clone();
+    state2.get_mut_by_id::<TestNode>(id).unwrap().data = 20;
+    assert_eq!(state2.root().child.as_ref().unwrap().data, 20);
+    let old = state2.update(id, |node: &mut TestNode| std::mem::replace(&mut node.data, 30));
+    assert_eq!(old, Ok(20));
+    assert_eq!(state2.root().child.as_ref().unwrap().data, 30);
+    assert_eq!(state1.root().child.as_ref().unwrap().data, 2);
+    assert!(state2.get_mut_by_id::<ListNode>(id).is_none());
+    assert!(state2.update(id, |_: &mut ListNode| ()).is_err());
+}
+
+#[test]
+fn transaction_merges_paths() {
+    let state1 = State::construct(|| {
+        let mut root = ListNode::new(0);
+        for i in 1..=3 {
+            let mut child = ListNode::new(i);
+            child.list.push(Child::with_parent(&child.header, ListNode::new(10 * i)));
+            root.list.push(Child::with_parent(&root.header, child));
+        }
+        root
+    });
+    let leaves: Vec<_> = state1.root().list.iter().map(|child| child.list[0].get_id()).collect();
+    let middle = state1.root().list[1].get_id();
+    let mut state2 = state1.clone();
+    let count = state2.transaction(|tx| {
+        for &leaf in &leaves {
+            tx.get_mut::<ListNode>(leaf).unwrap().data += 1;
+        }
+        let middle = tx.get_mut::<ListNode>(middle).unwrap();
+        middle.data = 200;
+        middle.list.push(Child::with_parent(middle.header.id, ListNode::new(300)));
+        assert!(tx.get_mut::<TestNode>(leaves[0]).is_none());
+        leaves.len()
+    });
+    assert_eq!(count, 3);
+    let root = state2.root();
+    assert_eq!(root.list.iter().map(|child| child.list[0].data).collect::<Vec<_>>(), vec![11, 21, 31]);
+    assert_eq!(root.list[1].data, 200);
+    let added = root.list[1].list[1].get_id();
+    assert_eq!(state2.get::<ListNode>(added).unwrap().data, 300);
+    let diff = State::diff(&state1, &state2);
+    assert_eq!(diff, Diff {
+        added: vec![added],
+        removed: vec![],
+        modified: {
+            let mut modified = leaves.clone();
+            modified.extend(root.list.iter().map(|child| child.get_id()));
+            modified.push(root.header.id);
+            modified.sort_unstable();
+            modified
+        },
+    });
+    // later edits still find the new versions
+    state2.get_mut_by_id::<ListNode>(leaves[2]).unwrap().data = 0;
+    assert_eq!(state2.root().list[2].list[0].data, 0);
+    assert_eq!(state1.root().list[2].list[0].data, 30);
+
+    // editing through the parent is only picked up before `get_mut`
+    state2.transaction(|tx| {
+        tx.get_mut::<ListNode>(middle).unwrap().list[0].make_mut().data += 100;
+        tx.get_mut::<ListNode>(leaves[1]).unwrap().data += 1000;
+    });
+    assert_eq!(state2.root().list[1].list[0].data, 1121);
+    let result = state2.try_transaction(|tx| {
+        tx.get_mut::<ListNode>(leaves[1]).unwrap().data = 0;
+        tx.get_mut::<ListNode>(middle).unwrap().list[0].make_mut().data = 0;
+        Ok::<_, CodError>(())
+    });
+    assert_eq!(result, Err(CodError::EditedTwice(leaves[1])));
+    assert_eq!(state2.root().list[1].list[0].data, 1121);
+}
+
+#[test]
+fn failed_transactions_roll_back() {
+    let mut state = State::new(&TestNode::new(1, Some(TestNode::new(2, None))));
+    let root_id = state.root().header.id;
+    let child_id = state.root().child.as_ref().unwrap().get_id();
+    #[derive(Debug, PartialEq)]
+    enum EditError {
+        Invalid,
+        Cod(CodError),
+    }
+    impl From<CodError> for EditError {
+        fn from(error: CodError) -> Self { EditError::Cod(error) }
+    }
+    let result: Result<(), EditError> = state.try_transaction(|tx| {
+        let root = tx.get_mut::<TestNode>(root_id).unwrap();
+        root.data = 10;
+        root.child = None;
+        Err(EditError::Invalid)
+    });
+    assert_eq!(result, Err(EditError::Invalid));
+    assert_eq!(state.root().data, 1);
+    assert!(state.get::<TestNode>(child_id).is_some());
+
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        state.transaction(|tx| {
+            tx.get_mut::<TestNode>(child_id).unwrap().data = 20;
+            panic!("validation failed");
+        })
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(state.root().child.as_ref().unwrap().data, 2);
+
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut root = state.get_mut(state.root_ref());
+        root.second_child = Some(Child::with_parent(root_id, TestNode::new(3, None)));
+        panic!("validation failed");
+    }));
+    assert!(panicked.is_err());
+    assert!(state.root().second_child.is_none());
+
+    // the context is usable again
+    let result: Result<i32, CodError> = state.try_transaction(|tx| {
+        tx.get_mut::<TestNode>(child_id).unwrap().data = 30;
+        Ok(30)
+    });
+    assert_eq!(result, Ok(30));
+    assert_eq!(state.root().child.as_ref().unwrap().data, 30);
+}
+
+/// Implements `poll_all_mut` incorrectly, with `poll()`
+#[derive(Clone, Debug)]
+struct BadNode {
+    header: Header,
+    child: Option<Child<BadNode>>,
+}
+
+impl Node for BadNode {
+    fn header(&self) -> &Header { &self.header }
+    fn header_mut(&mut self) -> &mut Header { &mut self.header }
+    fn poll_all(&self) { self.child.poll_all(); }
+    fn poll_all_mut(&mut self) { self.child.poll_all(); }
+    fn implements_poll_all(&self) -> bool { true }
+}
+
+impl BadNode {
+    fn chain(depth: usize) -> Self {
+        let mut node = BadNode { header: Header::new(), child: None };
+        if depth > 1 {
+            node.child = Some(Child::with_parent(&node, BadNode::chain(depth - 1)));
+        }
+        node
+    }
+}
+
+#[test]
+fn errors_instead_of_panics() {
+    let mut state = State::construct(|| BadNode::chain(3));
+    let mut other = State::construct(|| BadNode::chain(1));
+    let before = state.clone();
+
+    // deep copying the middle node polls the last one with `poll()`
+    let mut root = state.try_get_mut(state.root_ref()).unwrap();
+    let copy = root.child.clone();
+    root.child = copy;
+    assert!(matches!(root.try_commit(), Err(CodError::PollNotMut(_))));
+    assert!(State::diff(&before, &state).is_empty());
+
+    let foreign = other.root_ref();
+    assert_eq!(state.try_get_mut(foreign).err(), Some(CodError::NotInState(other.root().header.id)));
+
+    // the context is usable again
+    other.get_mut(other.root_ref()).child = None;
+    state.get_mut(state.root_ref()).child = None;
+    assert_eq!(state.ref_from_id(before.root().child.as_ref().unwrap().get_id()).map(|_| ()), None);
+
+    // a parent ID that is not in the state
+    let unknown = crate::ID::MAX / 2;
+    let mut state = State::construct(|| {
+        let mut root = TestNode::new(0, None);
+        root.child = Some(Child::with_parent(unknown, TestNode::new(1, None)));
+        root
+    });
+    let child = state.root().child.as_ref().unwrap().get_ref();
+    let mut child = state.try_get_mut(child).unwrap();
+    child.data = 2;
+    assert_eq!(child.try_commit(), Err(CodError::ChildNotFound { parent: unknown }));
+}
+
+#[test]
+fn interleaved_sessions() {
+    let mut doc1 = State::construct(|| ListNode::new(1));
+    let mut doc2 = State::construct(|| ListNode::new(2));
+    let (id1, id2) = (doc1.root().header.id, doc2.root().header.id);
+    let mut root1 = doc1.get_mut(doc1.root_ref());
+    let mut root2 = doc2.get_mut(doc2.root_ref());
+    let node1: &mut ListNode = &mut root1;
+    node1.list.push(Child::with_parent(id1, ListNode::new(10)));
+    let node2: &mut ListNode = &mut root2;
+    node2.list.push(Child::with_parent(id2, ListNode::new(20)));
+    let node1: &mut ListNode = &mut root1;
+    node1.list.push(Child::with_parent(id1, ListNode::new(11)));
+    let added1: Vec<_> = root1.list.iter().map(Child::get_id).collect();
+    let added2 = root2.list[0].get_id();
+    drop(root1);
+    drop(root2);
+    for &id in &added1 {
+        assert!(doc1.get::<ListNode>(id).is_some());
+        assert!(doc2.get::<ListNode>(id).is_none());
+    }
+    assert!(doc2.get::<ListNode>(added2).is_some());
+
+    // sessions nest inside a transaction
+    let (doc3, added) = doc1.transaction(|tx| {
+        let doc3 = State::construct(|| ListNode::new(3));
+        doc2.get_mut(doc2.root_ref()).list.clear();
+        let root = tx.get_mut::<ListNode>(id1).unwrap();
+        root.list.push(Child::with_parent(id1, ListNode::new(12)));
+        (doc3, root.list[2].get_id())
+    });
+    assert!(doc1.get::<ListNode>(added).is_some());
+    assert!(doc2.get::<ListNode>(added2).is_none());
+    assert_eq!(doc3.root().data, 3);
+
+    // cloning through one `MutRef` after writing through another
+    let mut root1 = doc1.get_mut(doc1.root_ref());
+    let mut root2 = doc2.get_mut(doc2.root_ref());
+    root2.data = 20;
+    let copy = root1.list[0].clone();
+    let copy_id = copy.get_id();
+    root1.list.push(copy);
+    drop(root1);
+    drop(root2);
+    assert!(doc1.get::<ListNode>(copy_id).is_some());
+    assert!(doc2.get::<ListNode>(copy_id).is_none());
+    assert_eq!(doc1.root().list[3].data, 10);
+
+    // a `&mut` from one `MutRef` used after another `MutRef` became current
+    let (before1, before2) = (doc1.clone(), doc2.clone());
+    let mut root1 = doc1.get_mut(doc1.root_ref());
+    let node1: &mut ListNode = &mut root1;
+    let mut root2 = doc2.get_mut(doc2.root_ref());
+    node1.list.push(Child::new(ListNode::new(13)));
+    let stray = node1.list[4].get_id();
+    root2.data = 21;
+    assert_eq!(root2.try_commit(), Err(CodError::WrongSession(stray)));
+    assert_eq!(root1.try_commit(), Err(CodError::WrongSession(stray)));
+    assert!(State::diff(&before1, &doc1).is_empty());
+    assert!(State::diff(&before2, &doc2).is_empty());
+    assert!(doc2.get::<ListNode>(stray).is_none());
+    assert_eq!((doc1.root().list.len(), doc2.root().data), (4, 20));
+}
+
+#[test]
+fn million_deep_chain() {
+    const DEPTH: i32 = 1_000_000;
+    fn deepest(root: &TestNode) -> &TestNode {
+        let mut node = root;
+        while let Some(child) = &node.child {
+            node = child;
+        }
+        node
+    }
+    let state1 = State::construct(|| {
+        let mut node = TestNode::new(DEPTH - 1, None);
+        for data in (0..DEPTH - 1).rev() {
+            node = TestNode::new(data, Some(node));
+        }
+        node
+    });
+    let deepest_id = deepest(state1.root()).header.id;
+
+    let copy = State::new(state1.root());
+    assert_eq!(deepest(copy.root()).data, DEPTH - 1);
+    assert_ne!(deepest(copy.root()).header.id, deepest_id);
+
+    let mut state2 = state1.clone();
+    state2.get_mut_by_id::<TestNode>(deepest_id).unwrap().data = -1;
+    assert_eq!(deepest(state2.root()).data, -1);
+
+    state2.get_mut(state2.root_ref()).child = None;
+    assert!(state2.get::<TestNode>(deepest_id).is_none());
+    assert!(state1.get::<TestNode>(deepest_id).is_some());
+    drop(state1);
+    drop(copy);
+}
+
+#[test]
+fn edit_in_large_vector() {
+    const LEN: usize = 50_000;
+    let mut state1 = State::construct(|| {
+        let mut root = ListNode::new(0);
+        root.list.push(Child::with_parent(&root.header, ListNode::new(-10)));
+        for i in 0..LEN {
+            root.vector.push_back(Child::with_parent(&root.header, ListNode::new(i as i32)));
+        }
+        root
+    });
+    let root_id = state1.root().header.id;
+    let ids: Vec<_> = state1.root().vector.iter().map(Child::get_id).collect();
+    // the first edit looks through the vector, the next ones know where to look
+    let mut state2 = state1.clone();
+    for index in [LEN - 1, 0, LEN / 2] {
+        state2.update(ids[index], |node: &mut ListNode| node.data = -1).unwrap();
+        assert_eq!(state2.root().vector[index].data, -1);
+    }
+    assert!(crate::Rc::ptr_eq(&state1.root().vector[1].get_ref(), &state2.root().vector[1].get_ref()));
+    assert_eq!(state1.root().vector[LEN / 2].data, (LEN / 2) as i32);
+
+    // positions shift a little, then a lot
+    state2.get_mut(state2.root_ref()).vector.push_front(Child::with_parent(root_id, ListNode::new(-2)));
+    state2.update(ids[LEN / 2], |node: &mut ListNode| node.data = -3).unwrap();
+    assert_eq!(state2.root().vector[LEN / 2 + 1].data, -3);
+    state2.get_mut(state2.root_ref()).vector.slice(..100);
+    state2.update(ids[LEN / 2], |node: &mut ListNode| node.data = -4).unwrap();
+    assert_eq!(state2.root().vector[LEN / 2 - 99].data, -4);
+
+    // other fields are still found
+    let list_id = state2.root().list[0].get_id();
+    state2.update(list_id, |node: &mut ListNode| node.data = -5).unwrap();
+    assert_eq!(state2.root().list[0].data, -5);
+    state1.update(list_id, |node: &mut ListNode| node.data = -6).unwrap();
+    assert_eq!(state1.root().list[0].data, -6);
+}
+
+thread_local! {
+    static CLONES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Counts how many times it is cloned
+#[derive(Node, Debug)]
+struct Counted {
+    header: Header,
+    data: i32,
+    next: Option<Child<Counted>>,
+}
+
+impl Clone for Counted {
+    fn clone(&self) -> Self {
+        CLONES.with(|clones| clones.set(clones.get() + 1));
+        Counted { header: self.header.clone(), data: self.data, next: self.next.clone() }
+    }
+}
+
+#[test]
+fn make_mut_copies_once() {
+    let state1 = State::construct(|| {
+        let mut root = Counted { header: Header::new(), data: 0, next: None };
+        let mut middle = Counted { header: Header::new(), data: 0, next: None };
+        middle.next = Some(Child::with_parent(&middle, Counted { header: Header::new(), data: 0, next: None }));
+        root.next = Some(Child::with_parent(&root, middle));
+        root
+    });
+    let leaf_id = state1.root().next.as_ref().unwrap().next.as_ref().unwrap().get_id();
+    let mut state2 = state1.clone();
+    CLONES.with(|clones| clones.set(0));
+    {
+        let mut root = state2.get_mut(state2.root_ref());
+        for _ in 0..100 {
+            let mut middle = root.next.as_mut().unwrap().make_mut();
+            middle.data += 1;
+            middle.next.as_mut().unwrap().make_mut().data += 1;
+        }
+    }
+    assert_eq!(CLONES.with(|clones| clones.get()), 3);
+    assert_eq!(state2.root().next.as_ref().unwrap().data, 100);
+    assert_eq!(state2.get::<Counted>(leaf_id).unwrap().data, 100);
+    assert_eq!(state1.get::<Counted>(leaf_id).unwrap().data, 0);
+
+    // nodes copied in an earlier session are shared with the old state
+    state2.get_mut(state2.root_ref()).next.as_mut().unwrap().make_mut().data += 1;
+    assert_eq!(CLONES.with(|clones| clones.get()), 5);
+    assert_eq!(state2.get::<Counted>(leaf_id).unwrap().data, 100);
+}
+
+#[test]
+fn navigation() {
+    let state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        for i in 1..=2 {
+            let mut child = ListNode::new(i);
+            child.list.push(Child::with_parent(&child.header, ListNode::new(10 * i)));
+            root.list.push(Child::with_parent(&root.header, child));
+        }
+        root
+    });
+    let root = state.root().header.id();
+    let middle: Vec<_> = state.root().list.iter().map(Child::get_id).collect();
+    let leaves: Vec<_> = state.root().list.iter().map(|child| child.list[0].get_id()).collect();
+
+    assert_eq!(state.parent_of(leaves[0]), Some(middle[0]));
+    assert_eq!(state.parent_of(root), None);
+    assert_eq!(state.ancestors(leaves[1]).collect::<Vec<_>>(), vec![middle[1], root]);
+    assert_eq!(state.ancestors(root).count(), 0);
+    assert_eq!(state.depth(leaves[0]), Some(2));
+    assert_eq!(state.depth(root), Some(0));
+    assert_eq!(state.path_from_root(leaves[0]), vec![root, middle[0], leaves[0]]);
+    assert_eq!(state.lowest_common_ancestor(leaves[0], leaves[1]), Some(root));
+    assert_eq!(state.lowest_common_ancestor(leaves[1], middle[1]), Some(middle[1]));
+    assert_eq!(state.lowest_common_ancestor(leaves[0], leaves[0]), Some(leaves[0]));
+
+    let missing = crate::id::new_id();
+    assert_eq!(state.depth(missing), None);
+    assert!(state.path_from_root(missing).is_empty());
+    assert_eq!(state.lowest_common_ancestor(missing, root), None);
+}
+
+#[test]
+fn walk() {
+    let state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        for i in 1..=2 {
+            let mut child = ListNode::new(i);
+            child.list.push(Child::with_parent(&child.header, ListNode::new(10 * i)));
+            root.list.push(Child::with_parent(&root.header, child));
+        }
+        root.optional = Some(Child::with_parent(&root.header, TestNode::new(3, Some(TestNode::new(30, None)))));
+        root
+    });
+    let root = state.root().header.id();
+    let middle: Vec<_> = state.root().list.iter().map(Child::get_id).collect();
+    let leaves: Vec<_> = state.root().list.iter().map(|child| child.list[0].get_id()).collect();
+    let test_node = state.root().optional.as_ref().unwrap();
+    let test_leaf = test_node.child.as_ref().unwrap().get_id();
+    let test_node = test_node.get_id();
+
+    let mut children = Vec::new();
+    state.root().for_each_child(&mut |child| children.push(child.header().id()));
+    assert_eq!(children, vec![middle[0], middle[1], test_node]);
+
+    let ids = |walk: crate::Walk| walk.map(|node| node.header().id()).collect::<Vec<_>>();
+    assert_eq!(ids(state.walk()), vec![root, middle[0], leaves[0], middle[1], leaves[1], test_node, test_leaf]);
+    assert_eq!(ids(state.walk().breadth_first()), vec![root, middle[0], middle[1], test_node, leaves[0], leaves[1], test_leaf]);
+    // children of nodes without a derived implementation are found too
+    assert_eq!(ids(state.walk_subtree(test_node)), vec![test_node, test_leaf]);
+    assert_eq!(ids(state.walk_subtree(middle[1])), vec![middle[1], leaves[1]]);
+    assert!(state.walk_subtree(crate::id::new_id()).next().is_none());
+}
+
+#[test]
+fn iter_of_type() {
+    let unindexed = State::construct(|| {
+        let mut root = ListNode::new(0);
+        for i in 1..=3 {
+            root.list.push(Child::with_parent(&root.header, ListNode::new(i)));
+        }
+        root.optional = Some(Child::with_parent(&root.header, TestNode::new(4, Some(TestNode::new(5, None)))));
+        root
+    });
+    assert!(!unindexed.has_type_index());
+    let mut state = unindexed.clone().with_type_index();
+    assert!(state.has_type_index());
+    let data = |state: &State<ListNode>| {
+        let mut data: Vec<_> = state.iter_of_type::<ListNode>().map(|node| node.data).collect();
+        data.sort_unstable();
+        data
+    };
+    assert_eq!(data(&state), data(&unindexed));
+    assert_eq!(data(&state), vec![0, 1, 2, 3]);
+    assert_eq!(state.count_of_type::<TestNode>(), 2);
+    assert_eq!(unindexed.count_of_type::<TestNode>(), 2);
+    assert_eq!(state.count_of_type::<Counted>(), 0);
+
+    // kept in sync with edits, and carried over to derived states
+    let root_id = state.root().header.id();
+    state.get_mut(state.root_ref()).list.push(Child::with_parent(root_id, ListNode::new(6)));
+    state.get_mut(state.root_ref()).optional = None;
+    assert_eq!(data(&state), vec![0, 1, 2, 3, 6]);
+    assert_eq!(state.count_of_type::<TestNode>(), 0);
+    let removed = state.root().list[0].get_id();
+    state.transaction(|tx| {
+        let root = tx.get_mut::<ListNode>(root_id).unwrap();
+        root.list.remove(0);
+        let first = root.list[0].get_id();
+        root.list[0].make_mut().list.push(Child::with_parent(first, ListNode::new(7)));
+    });
+    assert_eq!(data(&state), vec![0, 2, 3, 6, 7]);
+    assert!(state.iter_of_type::<ListNode>().all(|node| node.header.id() != removed));
+    assert_eq!(unindexed.count_of_type::<ListNode>(), 4);
+}
+
+#[derive(Clone, Node)]
+struct Linked {
+    header: Header,
+    target: NodeRef<Linked>,
+    children: Vec<Child<Linked>>,
+}
+
+impl Linked {
+    fn new() -> Linked {
+        Linked {
+            header: Header::new(),
+            target: NodeRef::null(),
+            children: Vec::new(),
+        }
+    }
+}
+
+#[test]
+fn node_refs() {
+    let mut state = State::construct(|| {
+        let mut root = Linked::new();
+        let mut group = Linked::new();
+        let mut first = Linked::new();
+        let mut second = Linked::new();
+        // one reference within the group, one outside of it
+        first.target = NodeRef::to(&second);
+        second.target = NodeRef::to(&root);
+        group.children.push(Child::with_parent(&group.header, first));
+        group.children.push(Child::with_parent(&group.header, second));
+        root.children.push(Child::with_parent(&root.header, group));
+        root
+    });
+    let root_id = state.root().header.id();
+    let group = &state.root().children[0];
+    let group_id = group.get_id();
+    let (first, second) = (group.children[0].get_id(), group.children[1].get_id());
+    let target = group.children[0].target.clone();
+    assert_eq!(target.id(), Some(second));
+    assert_eq!(target.resolve(&state).unwrap().header.id(), second);
+    assert!(NodeRef::<ListNode>::new(second).resolve(&state).is_none());
+    assert!(matches!(NodeRef::<ListNode>::new(second).try_resolve(&state), Err(LookupError::WrongType { .. })));
+    // references are not children
+    assert_eq!(state.walk().count(), 4);
+
+    state.transaction(|tx| {
+        let root = tx.get_mut::<Linked>(root_id).unwrap();
+        let plain = root.children[0].clone();
+        let remapped = root.children[0].clone_remapping_refs();
+        root.children.push(plain);
+        root.children.push(remapped);
+    });
+    let plain = &state.root().children[1];
+    assert_eq!(plain.children[0].target.id(), Some(second));
+    let remapped = &state.root().children[2];
+    assert_ne!(remapped.get_id(), group_id);
+    assert_ne!(remapped.children[0].get_id(), first);
+    assert_eq!(remapped.children[0].target.id(), Some(remapped.children[1].get_id()));
+    assert_eq!(remapped.children[1].target.id(), Some(root_id));
+    // cloning outside of a deep copy keeps the target
+    assert_eq!(remapped.children[0].target.clone().id(), Some(remapped.children[1].get_id()));
+
+    // removing the target leaves the reference dangling
+    state.get_mut(state.root().children[0].get_ref()).children.remove(1);
+    assert!(target.resolve(&state).is_none());
+    assert!(matches!(target.try_resolve(&state), Err(LookupError::NotFound(id)) if id == second));
+    assert!(matches!(NodeRef::<Linked>::null().try_resolve(&state), Ok(None)));
+}
+
+#[test]
+fn backlinks() {
+    use crate::RefPolicy;
+    // shapes, arrows to them, and a note on the label of one arrow
+    let unindexed = State::construct(|| {
+        let mut root = Linked::new();
+        let first = Linked::new();
+        let second = Linked::new();
+        let mut nullified = Linked::new();
+        nullified.target = NodeRef::to(&first);
+        let mut cascading = Linked::new();
+        cascading.target = NodeRef::to(&first).on_remove(RefPolicy::Cascade);
+        let label = Linked::new();
+        let mut note = Linked::new();
+        note.target = NodeRef::to(&label).on_remove(RefPolicy::Cascade);
+        cascading.children.push(Child::with_parent(&cascading.header, label));
+        let mut rejecting = Linked::new();
+        rejecting.target = NodeRef::to(&second).on_remove(RefPolicy::Reject);
+        for node in [first, second, nullified, cascading, rejecting, note] {
+            root.children.push(Child::with_parent(&root.header, node));
+        }
+        root
+    });
+    let mut state = unindexed.clone().with_backlinks();
+    assert!(state.has_backlinks() && !unindexed.has_backlinks());
+    let ids: Vec<_> = state.root().children.iter().map(Child::get_id).collect();
+    let [first, second, nullified, cascading, rejecting, note] = ids[..] else { unreachable!() };
+    let label = state.root().children[3].children[0].get_id();
+    let root_id = state.root().header.id();
+    assert_eq!(state.referrers(first), vec![nullified, cascading]);
+    assert_eq!(unindexed.referrers(first), vec![nullified, cascading]);
+    assert_eq!(state.referrers(label), vec![note]);
+    assert!(state.referrers(nullified).is_empty());
+
+    // rejected, so nothing changes
+    let result = state.try_transaction(|tx| {
+        tx.get_mut::<Linked>(root_id).unwrap().children.retain(|child| child.get_id() != second);
+        Ok::<_, CodError>(())
+    });
+    assert_eq!(result, Err(CodError::StillReferenced { id: second, referrer: rejecting }));
+    let result = state.remove(second);
+    assert_eq!(result, Err(EditError::Cod(CodError::StillReferenced { id: second, referrer: rejecting })));
+    assert!(state.ref_from_id(second).is_some());
+
+    // nullified, and cascading through the label to the note
+    state.get_mut(state.root_ref()).children.remove(0);
+    let remaining: Vec<_> = state.root().children.iter().map(Child::get_id).collect();
+    assert_eq!(remaining, vec![second, nullified, rejecting]);
+    assert!(state.root().children[1].target.is_null());
+    for removed in [first, cascading, label, note] {
+        assert!(state.ref_from_id(removed).is_none());
+    }
+    assert!(state.referrers(first).is_empty());
+    assert!(state.referrers(label).is_empty());
+    assert_eq!(state.referrers(second), vec![rejecting]);
+    // without backlinks, references are left dangling
+    let mut dangling = unindexed.clone();
+    dangling.get_mut(dangling.root_ref()).children.remove(0);
+    assert_eq!(dangling.root().children[2].target.id(), Some(first));
+    assert_eq!(dangling.root().children.len(), 5);
+
+    // new references are picked up, and the root cannot be cascaded
+    state.update(nullified, |node: &mut Linked| node.target = NodeRef::new(rejecting).on_remove(RefPolicy::Cascade))
+        .unwrap();
+    assert_eq!(state.referrers(rejecting), vec![nullified]);
+    state.get_mut(state.root_ref()).target = NodeRef::new(nullified).on_remove(RefPolicy::Cascade);
+    let result = state.try_transaction(|tx| {
+        tx.get_mut::<Linked>(root_id).unwrap().children.retain(|child| child.get_id() != nullified);
+        Ok::<_, CodError>(())
+    });
+    assert_eq!(result, Err(CodError::CannotRemove(root_id)));
+
+    // a referrer whose parent ID is not in the state cannot be cascaded either
+    let unknown = crate::ID::MAX / 2;
+    let mut orphaned = State::construct(|| {
+        let mut root = Linked::new();
+        let target = Linked::new();
+        let mut referrer = Linked::new();
+        referrer.target = NodeRef::to(&target).on_remove(RefPolicy::Cascade);
+        root.children.push(Child::with_parent(&root.header, target));
+        root.children.push(Child::with_parent(unknown, referrer));
+        root
+    }).with_backlinks();
+    let target = orphaned.root().children[0].get_id();
+    assert_eq!(orphaned.remove(target), Err(EditError::Cod(CodError::ChildNotFound { parent: unknown })));
+    assert!(orphaned.ref_from_id(target).is_some());
+}
+
+#[test]
+fn move_node() {
+    let mut state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        let mut first = ListNode::new(1);
+        let mut inner = ListNode::new(10);
+        inner.list.push(Child::with_parent(&inner.header, ListNode::new(100)));
+        first.list.push(Child::with_parent(&first.header, inner));
+        root.list.push(Child::with_parent(&root.header, first));
+        root.vector.push_back(Child::with_parent(&root.header, ListNode::new(2)));
+        root
+    });
+    let old = state.clone();
+    let root = state.root().header.id();
+    let first = state.root().list[0].get_id();
+    let second = state.root().vector[0].get_id();
+    let inner = state.root().list[0].list[0].get_id();
+    let leaf = state.root().list[0].list[0].list[0].get_id();
+
+    state.move_node(inner, second, |parent: &mut ListNode, child| parent.list.push(child)).unwrap();
+    assert!(state.root().list[0].list.is_empty());
+    let moved = &state.root().vector[0].list[0];
+    assert_eq!((moved.get_id(), moved.data), (inner, 10));
+    assert_eq!(state.path_from_root(leaf), vec![root, second, inner, leaf]);
+    let diff = State::diff(&old, &state);
+    assert!(diff.added.is_empty() && diff.removed.is_empty());
+    assert_eq!(old.path_from_root(leaf), vec![root, first, inner, leaf]);
+
+    // within the same parent, from an `im::Vector` to a `Vec`, along with other edits
+    let mut added = None;
+    state.transaction(|tx| {
+        tx.get_mut::<ListNode>(second).unwrap().data = 20;
+        let root_node = tx.get_mut::<ListNode>(root).unwrap();
+        root_node.list.push(Child::with_parent(root, ListNode::new(3)));
+        added = Some(root_node.list[1].get_id());
+        tx.move_node(second, root, |parent: &mut ListNode, child| parent.list.insert(0, child)).unwrap();
+        tx.move_node(added.unwrap(), inner, |parent: &mut ListNode, child| parent.list.push(child)).unwrap();
+    });
+    let ids: Vec<_> = state.root().list.iter().map(Child::get_id).collect();
+    assert_eq!(ids, vec![second, first]);
+    assert!(state.root().vector.is_empty());
+    assert_eq!(state.root().list[0].data, 20);
+    assert_eq!(state.path_from_root(added.unwrap()), vec![root, second, inner, added.unwrap()]);
+
+    // misuse is reported where it happens, leaving the state unchanged
+    let into_subtree = state.move_node(second, leaf, |parent: &mut ListNode, child| parent.list.push(child));
+    assert_eq!(into_subtree, Err(EditError::Cod(CodError::MoveIntoSubtree(second))));
+    let root_into_leaf = state.try_transaction(|tx| {
+        tx.get_mut::<ListNode>(leaf).unwrap().data = 1000;
+        tx.move_node(root, leaf, |parent: &mut ListNode, child| parent.list.push(child))
+    });
+    assert_eq!(root_into_leaf, Err(EditError::Cod(CodError::MoveIntoSubtree(root))));
+    let unknown = crate::ID::MAX / 2;
+    let mut orphaned = State::construct(|| {
+        let mut root = ListNode::new(0);
+        root.list.push(Child::with_parent(unknown, ListNode::new(1)));
+        root
+    });
+    let (orphaned_root, orphan) = (orphaned.root().header.id, orphaned.root().list[0].get_id());
+    let from_unknown = orphaned.move_node(orphan, orphaned_root, |parent: &mut ListNode, child| parent.list.push(child));
+    assert_eq!(from_unknown, Err(EditError::Cod(CodError::ChildNotFound { parent: unknown })));
+    assert_eq!(orphaned.root().list.len(), 1);
+    assert_eq!(state.path_from_root(leaf), vec![root, second, inner, leaf]);
+    assert_eq!(state.get::<ListNode>(leaf).unwrap().data, 100);
+    // the transaction can carry on after a failed move
+    state.transaction(|tx| {
+        assert!(tx.move_node(root, leaf, |parent: &mut ListNode, child| parent.list.push(child)).is_err());
+        tx.get_mut::<ListNode>(leaf).unwrap().data = 1000;
+    });
+    assert_eq!(state.get::<ListNode>(leaf).unwrap().data, 1000);
+    let wrong_type = state.move_node(leaf, first, |parent: &mut TestNode, child| parent.child = Some(child));
+    assert!(matches!(wrong_type, Err(EditError::Lookup(LookupError::WrongType { .. }))));
+}
+
+#[test]
+fn insert_and_remove_by_id() {
+    let mut state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        root.vector.push_back(Child::with_parent(&root.header, ListNode::new(1)));
+        root.optional = Some(Child::with_parent(&root.header, TestNode::new(2, Some(TestNode::new(3, None)))));
+        root
+    });
+    let root = state.root().header.id();
+    let in_vector = state.root().vector[0].get_id();
+    let test_node = state.root().optional.as_ref().unwrap();
+    let test_leaf = test_node.child.as_ref().unwrap().get_id();
+    let test_node = test_node.get_id();
+
+    // built outside of any mutation session
+    let mut node = ListNode::new(4);
+    node.list.push(Child::with_parent(&node.header, ListNode::new(40)));
+    let leaf = node.list[0].get_id();
+    let inserted = state.insert_child(in_vector, node, |parent: &mut ListNode, child| parent.list.push(child)).unwrap();
+    assert_eq!(state.path_from_root(leaf), vec![root, in_vector, inserted, leaf]);
+    assert_eq!(state.get::<ListNode>(leaf).unwrap().data, 40);
+    let wrong_type = state.insert_child(root, ListNode::new(5), |_: &mut TestNode, _| unreachable!());
+    assert!(matches!(wrong_type, Err(EditError::Lookup(LookupError::WrongType { .. }))));
+    // keeps its IDs, which are taken
+    let copy = (*state.root().vector[0]).clone();
+    let result = state.insert_child(root, copy, |parent: &mut ListNode, child| parent.list.push(child));
+    assert_eq!(result, Err(EditError::Cod(CodError::DuplicateID(in_vector))));
+    assert!(state.root().list.is_empty());
+    assert_eq!(state.path_from_root(leaf), vec![root, in_vector, inserted, leaf]);
+
+    state.remove(inserted).unwrap();
+    assert!(state.root().vector[0].list.is_empty());
+    assert!(state.ref_from_id(inserted).is_none() && state.ref_from_id(leaf).is_none());
+    state.remove(in_vector).unwrap();
+    assert!(state.root().vector.is_empty());
+    assert_eq!(state.remove(in_vector), Err(EditError::Lookup(LookupError::NotFound(in_vector))));
+
+    // not derived, so the parent cannot do without its child
+    assert_eq!(state.remove(test_leaf), Err(EditError::Cod(CodError::CannotRemove(test_leaf))));
+    assert_eq!(state.remove(root), Err(EditError::Cod(CodError::CannotRemove(root))));
+    state.transaction(|tx| {
+        assert!(tx.remove(root).is_err());
+        tx.get_mut::<TestNode>(test_leaf).unwrap().data = 30;
+    });
+    assert_eq!(state.get::<TestNode>(test_leaf).unwrap().data, 30);
+    state.remove(test_node).unwrap();
+    assert!(state.root().optional.is_none());
+    assert!(state.ref_from_id(test_leaf).is_none());
+    assert_eq!(state.walk().count(), 1);
+
+    // a parent ID that is not in the state
+    let unknown = crate::ID::MAX / 2;
+    let mut state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        root.list.push(Child::with_parent(unknown, ListNode::new(1)));
+        root
+    });
+    let child = state.root().list[0].get_id();
+    assert_eq!(state.remove(child), Err(EditError::Cod(CodError::ChildNotFound { parent: unknown })));
+    assert_eq!(state.root().list.len(), 1);
+}
+
+#[test]
+fn detached_children() {
+    let mut state = State::construct(|| {
+        let mut root = ListNode::new(0);
+        let mut inner = ListNode::new(1);
+        inner.list.push(Child::new(ListNode::new(2)));
+        root.list.push(Child::new(inner));
+        root
+    });
+    let root = state.root().header.id();
+    let inner = state.root().list[0].get_id();
+    let leaf = state.root().list[0].list[0].get_id();
+    assert_eq!(state.path_from_root(leaf), vec![root, inner, leaf]);
+
+    let mut node = state.get_mut_by_id::<ListNode>(inner).unwrap();
+    node.vector.push_back(Child::new(ListNode::new(3)));
+    let added = node.vector[0].get_id();
+    assert_eq!(node.vector[0].header.parent_id(), None);
+    drop(node);
+    assert_eq!(state.parent_of(added), Some(inner));
+
+    // edited again before the edit adding it is committed
+    let added = state.transaction(|tx| {
+        let parent = tx.get_mut::<ListNode>(leaf).unwrap();
+        parent.list.push(Child::new(ListNode::new(4)));
+        let id = parent.list[0].get_id();
+        tx.get_mut::<ListNode>(id).unwrap().data = 40;
+        id
+    });
+    assert_eq!(state.path_from_root(added), vec![root, inner, leaf, added]);
+    assert_eq!(state.root().list[0].list[0].list[0].data, 40);
+
+    // moved and removed in the transaction that added them
+    state.transaction(|tx| {
+        let parent = tx.get_mut::<ListNode>(root).unwrap();
+        parent.list.push(Child::new(ListNode::new(5)));
+        parent.list.push(Child::new(ListNode::new(6)));
+        let (moved, removed) = (parent.list[1].get_id(), parent.list[2].get_id());
+        assert_eq!(
+            tx.move_node(inner, moved, |parent: &mut ListNode, child| parent.list.push(child)),
+            Ok(())
+        );
+        assert_eq!(
+            tx.move_node(moved, inner, |parent: &mut ListNode, child| parent.list.push(child)),
+            Err(EditError::Cod(CodError::MoveIntoSubtree(moved)))
+        );
+        assert_eq!(tx.remove(removed), Ok(()));
+    });
+    let moved = state.root().list[0].get_id();
+    assert_eq!(state.root().list.len(), 1);
+    assert_eq!(state.root().list[0].data, 5);
+    assert_eq!(state.path_from_root(added), vec![root, moved, inner, leaf, added]);
+}
+
+/// Claims to implement `poll_all`, but reports no children
+#[derive(Clone)]
+struct Secretive {
+    header: Header,
+    child: Option<Child<Secretive>>,
+}
+
+impl Node for Secretive {
+    fn header(&self) -> &Header { &self.header }
+    fn header_mut(&mut self) -> &mut Header { &mut self.header }
+    fn implements_poll_all(&self) -> bool { true }
+}
+
+#[test]
+fn detached_children_of_manual_nodes() {
+    let mut state = State::construct(|| {
+        let mut root = TestNode::new(0, None);
+        root.child = Some(Child::new(TestNode::new(1, None)));
+        root
+    });
+    let root = state.root().header.id();
+    let child = state.root().child.as_ref().unwrap().get_ref();
+    assert_eq!(child.header.parent_id(), Some(root));
+    state.get_mut(child).data = 5;
+    assert_eq!(state.root().header.id(), root);
+    assert_eq!(state.root().child.as_ref().unwrap().data, 5);
+
+    let mut state = State::construct(|| Secretive {
+        header: Header::new(),
+        child: Some(Child::new(Secretive { header: Header::new(), child: None })),
+    });
+    let child = state.root().child.as_ref().unwrap().get_ref();
+    let id = child.header.id();
+    let mut child = state.try_get_mut(child).unwrap();
+    child.child = None;
+    assert_eq!(child.try_commit(), Err(CodError::Detached(id)));
+    assert!(state.root().child.is_some());
+}
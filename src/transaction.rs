@@ -0,0 +1,489 @@
+//! Editing several nodes in one mutation session, and propagating
+//! the edits up to the root.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::any::{Any, TypeId};
+use std::convert::Infallible;
+use crate::{NodeClone, State, Child, ID, Rc, Weak, Walk, LookupError, CodError, EditError, Indexes, apply_updates, adopt_children, new_versions, expect_ok};
+use crate::context::{with_context, Context, Session, PollReason, IDMapUpdate};
+use crate::backlinks::settle_refs;
+use crate::danger_zone::{downcast_rc, downcast_mut};
+
+/// Handle for editing nodes inside [`State::transaction`].
+///
+/// All edits are part of a single mutation session, and are committed to
+/// the state together when the transaction ends.
+pub struct Transaction<'a, R: NodeClone + Clone> {
+    state: &'a mut State<R>,
+    /// ID lookup including the changes made so far, not yet committed to `state`
+    id_lookup: crate::im::HashMap<ID, Weak<dyn NodeClone>>,
+    /// Indexes of the state, including the changes made so far
+    indexes: Indexes,
+    /// Unique working copies of the nodes being edited
+    edited: HashMap<ID, Rc<dyn NodeClone>>,
+    session: Session,
+    /// Misuse noticed while catching up with the mutation session, failing the commit
+    error: Option<CodError>,
+    /// Nodes that got a new version in the mutation session
+    new_versions: Vec<ID>,
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// Edit any number of nodes in `edit`, and commit them as one change.
+    ///
+    /// Compared to calling [`get_mut`](State::get_mut) for each node, shared ancestors
+    /// are only updated once, and no intermediate states are created.
+    pub fn transaction<O>(&mut self, edit: impl FnOnce(&mut Transaction<'_, R>) -> O) -> O {
+        match expect_ok(self.run_transaction(|transaction| Ok::<O, Infallible>(edit(transaction)))) {
+            Ok(result) => result,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Like [`transaction`](State::transaction), but if `edit` returns `Err` or panics,
+    /// nothing is committed and the state is left as it was. The same goes for
+    /// a [`CodError`], which is converted into `E`.
+    pub fn try_transaction<O, E: From<CodError>>(&mut self, edit: impl FnOnce(&mut Transaction<'_, R>) -> Result<O, E>)
+        -> Result<O, E> {
+        self.run_transaction(edit).unwrap_or_else(|error| Err(error.into()))
+    }
+
+    /// Move the node with the given ID to a new parent, keeping the IDs of its whole
+    /// subtree. See [`Transaction::move_node`]. If this fails, the state is left unchanged.
+    pub fn move_node<T: NodeClone + Clone, P: NodeClone + Clone>(
+        &mut self,
+        id: ID,
+        new_parent_id: ID,
+        insert: impl FnOnce(&mut P, Child<T>),
+    ) -> Result<(), EditError> {
+        self.try_transaction(|transaction| transaction.move_node(id, new_parent_id, insert))
+    }
+
+    /// Add `node` as a child of the node with ID `parent_id`, and return the ID of `node`.
+    /// See [`Transaction::insert_child`]. If this fails, the state is left unchanged.
+    pub fn insert_child<P: NodeClone + Clone, T: NodeClone + Clone>(
+        &mut self,
+        parent_id: ID,
+        node: T,
+        insert: impl FnOnce(&mut P, Child<T>),
+    ) -> Result<ID, EditError> {
+        self.try_transaction(|transaction| transaction.insert_child(parent_id, node, insert))
+    }
+
+    /// Remove the node with the given ID along with its subtree.
+    /// See [`Transaction::remove`]. If this fails, the state is left unchanged.
+    pub fn remove(&mut self, id: ID) -> Result<(), EditError> {
+        self.try_transaction(|transaction| transaction.remove(id))
+    }
+
+    fn run_transaction<O, E>(&mut self, edit: impl FnOnce(&mut Transaction<'_, R>) -> Result<O, E>)
+        -> Result<Result<O, E>, CodError> {
+        let mut transaction = Transaction {
+            id_lookup: self.id_lookup.clone(),
+            indexes: self.indexes.clone(),
+            state: self,
+            edited: HashMap::new(),
+            session: Session::begin(),
+            error: None,
+            new_versions: Vec::new(),
+        };
+        let result = edit(&mut transaction);
+        if result.is_ok() {
+            transaction.commit()?;
+        }
+        Ok(result)
+    }
+
+    /// Commit the `edited` nodes, updating their ancestors so that they point to the
+    /// new versions, and set the new root. Ancestors shared by several edited nodes are
+    /// copied only once. `id_lookup` and `indexes` must include the updates from the
+    /// mutation session, and the session must have ended. `new_versions` are the IDs
+    /// set in the session, which must all be in the subtrees of the edited nodes.
+    /// If this fails, the state is left unchanged.
+    pub(crate) fn propagate(
+        &mut self,
+        mut id_lookup: crate::im::HashMap<ID, Weak<dyn NodeClone>>,
+        mut indexes: Indexes,
+        mut edited: HashMap<ID, Rc<dyn NodeClone>>,
+        new_versions: Vec<ID>,
+    ) -> Result<(), CodError> {
+        // not to be noticed by any live session
+        let _idle = Session::idle();
+        adopt_edited_children(&id_lookup, &edited)?;
+        check_new_versions(&id_lookup, &edited, new_versions)?;
+        settle_refs(&mut id_lookup, &mut indexes, &mut edited)?;
+        // nodes removed during the session need no propagation
+        let mut pending: HashMap<ID, Rc<dyn NodeClone>> = edited.into_iter()
+            .filter(|(id, _)| id_lookup.contains_key(id))
+            .collect();
+        let mut levels: BTreeMap<usize, Vec<ID>> = BTreeMap::new();
+        for (id, node) in &pending {
+            levels.entry(depth_of(&id_lookup, &pending, &**node)?).or_default().push(*id);
+        }
+        // old versions of edited parents, dropped after the context is inactive again
+        let mut replaced_parents = Vec::new();
+        let mut root = Rc::clone(&self.root);
+        let root_id = root.header().id;
+        while let Some((depth, ids)) = levels.pop_last() {
+            if depth == 0 {
+                // a node without a parent ID that is not the root must not replace it
+                if let Some(&id) = ids.iter().find(|&&id| id != root_id) {
+                    return Err(CodError::Detached(id))
+                }
+                let new_root = pending.remove(&ids[0]).unwrap();
+                id_lookup.insert(ids[0], Rc::downgrade(&new_root));
+                root = downcast_rc(new_root).unwrap();
+                break
+            }
+            let mut by_parent: HashMap<ID, HashMap<ID, Rc<dyn NodeClone>>> = HashMap::new();
+            for id in ids {
+                let node = pending.remove(&id).unwrap();
+                id_lookup.insert(id, Rc::downgrade(&node));
+                let parent_id = node.header().parent_id().unwrap();
+                by_parent.entry(parent_id).or_default().insert(id, node);
+            }
+            let parents = levels.entry(depth - 1).or_default();
+            for (parent_id, children) in by_parent {
+                let parent = match pending.remove(&parent_id) {
+                    Some(parent) => parent,
+                    None => {
+                        parents.push(parent_id);
+                        id_lookup.get(&parent_id).and_then(Weak::upgrade)
+                            .ok_or(CodError::ChildNotFound { parent: parent_id })?
+                    },
+                };
+                let new_parent = replace_children(parent_id, parent, children, &mut replaced_parents)?;
+                pending.insert(parent_id, new_parent);
+            }
+        }
+        self.root = root;
+        self.id_lookup = id_lookup;
+        self.indexes = indexes;
+        drop(replaced_parents);
+        Ok(())
+    }
+}
+
+/// Set the parent ID of the children created with [`Child::new`] in the `edited` nodes.
+/// Fails if a child is not in `id_lookup`, because it was created in another session.
+fn adopt_edited_children(
+    id_lookup: &crate::im::HashMap<ID, Weak<dyn NodeClone>>,
+    edited: &HashMap<ID, Rc<dyn NodeClone>>,
+) -> Result<(), CodError> {
+    for (&id, node) in edited {
+        if !id_lookup.contains_key(&id) { continue }
+        let mut result = Ok(());
+        adopt_children(&**node, |child_id| {
+            // the child may have been edited as well, after it was created
+            if let Some(child) = edited.get(&child_id) {
+                if child.header().parent_id().is_none() {
+                    child.header().set_parent_id(Some(id));
+                }
+            } else if !id_lookup.contains_key(&child_id) {
+                result = Err(CodError::WrongSession(child_id));
+            }
+        });
+        result?;
+    }
+    Ok(())
+}
+
+/// Fails if one of the `new_versions` still in `id_lookup` does not descend from an
+/// `edited` node. It was then created or copied while this session was current,
+/// but put into a node edited in another one.
+fn check_new_versions(
+    id_lookup: &crate::im::HashMap<ID, Weak<dyn NodeClone>>,
+    edited: &HashMap<ID, Rc<dyn NodeClone>>,
+    new_versions: Vec<ID>,
+) -> Result<(), CodError> {
+    // nodes known to descend from an edited node
+    let mut verified: HashSet<ID> = edited.keys().copied().collect();
+    for id in new_versions {
+        if !id_lookup.contains_key(&id) { continue }
+        let mut chain = Vec::new();
+        let mut ancestor = id;
+        while !verified.contains(&ancestor) {
+            let node = id_lookup.get(&ancestor).and_then(Weak::upgrade);
+            let parent_id = node.and_then(|node| node.header().parent_id());
+            // the parent IDs may also form a cycle
+            match parent_id {
+                Some(parent_id) if chain.len() <= id_lookup.len() => {
+                    chain.push(ancestor);
+                    ancestor = parent_id;
+                },
+                _ => return Err(CodError::WrongSession(id)),
+            }
+        }
+        verified.extend(chain);
+    }
+    Ok(())
+}
+
+/// Number of ancestors of `node`, which may be a new version not yet in the lookup.
+/// Ancestors are looked up in `pending` first, since they may have been moved.
+/// Fails if a parent ID does not lead to the root.
+fn depth_of(
+    id_lookup: &crate::im::HashMap<ID, Weak<dyn NodeClone>>,
+    pending: &HashMap<ID, Rc<dyn NodeClone>>,
+    node: &dyn NodeClone,
+) -> Result<usize, CodError> {
+    let mut depth = 0;
+    let mut parent_id = node.header().parent_id();
+    while let Some(id) = parent_id {
+        depth += 1;
+        // more ancestors than nodes, so the parent IDs form a cycle
+        if depth > id_lookup.len() {
+            return Err(CodError::ChildNotFound { parent: id })
+        }
+        parent_id = match pending.get(&id) {
+            Some(parent) => parent.header().parent_id(),
+            None => id_lookup.get(&id).and_then(Weak::upgrade)
+                .ok_or(CodError::ChildNotFound { parent: id })?
+                .header().parent_id(),
+        };
+    }
+    Ok(depth)
+}
+
+/// Returns a version of `parent` where the `Child`ren with the given IDs point
+/// to the given nodes. `parent` is edited in place if possible, otherwise the old
+/// version is put into `graveyard` so that it is not dropped too early.
+fn replace_children(
+    parent_id: ID,
+    mut parent: Rc<dyn NodeClone>,
+    children: HashMap<ID, Rc<dyn NodeClone>>,
+    graveyard: &mut Vec<Rc<dyn NodeClone>>,
+) -> Result<Rc<dyn NodeClone>, CodError> {
+    let ids: Vec<ID> = children.keys().copied().collect();
+    with_context(|c| {
+        Context::set_replacements(c, children);
+    });
+    let new_parent = if parent.implements_poll_child() {
+        if Rc::get_mut(&mut parent).is_none() {
+            // copy without polling any children, then poll only the ones to replace
+            graveyard.push(parent.clone());
+            parent = with_context(|c| Context::suspend(c, || parent.dyn_clone()));
+        }
+        let node = Rc::get_mut(&mut parent).unwrap();
+        for id in ids {
+            node.poll_child_mut(id);
+        }
+        parent
+    } else {
+        let new_parent = parent.dyn_clone();
+        graveyard.push(parent);
+        new_parent
+    };
+    with_context(|c| Context::finish_replacement(c, parent_id))?;
+    Ok(new_parent)
+}
+
+impl<'a, R: NodeClone + Clone> Transaction<'a, R> {
+    /// The state as it was before the transaction.
+    pub fn state(&self) -> &State<R> {
+        self.state
+    }
+
+    /// Get a mutable reference to the node with the given ID. Returns `None`
+    /// if there is no such node, or if it is of another type.
+    ///
+    /// The node is copied the first time it is accessed during the transaction.
+    /// Accessing a node here and also through `Child::make_mut` on its parent is
+    /// fine as long as the `make_mut` happens first. Otherwise one of the edits would
+    /// be lost, so committing fails with [`CodError::EditedTwice`].
+    pub fn get_mut<T: NodeClone + Clone>(&mut self, id: ID) -> Option<&mut T> {
+        self.try_get_mut(id).ok()
+    }
+
+    /// Like [`get_mut`](Transaction::get_mut), but tells why the node could not be found.
+    pub fn try_get_mut<T: NodeClone + Clone>(&mut self, id: ID) -> Result<&mut T, LookupError> {
+        self.sync();
+        self.check_type::<T>(id)?;
+        Ok(downcast_mut(self.edit(id)).unwrap())
+    }
+
+    /// Move the node with the given ID to the parent with ID `new_parent_id`, keeping the
+    /// IDs of its whole subtree. `insert` puts the `Child` into the new parent, which may
+    /// also be the old one. The ancestors of both parents are updated when committing.
+    ///
+    /// The node is taken out of its old parent with [`Node::take_child`](crate::Node::take_child).
+    /// If that is not possible, or the node would be moved into its own subtree, a
+    /// [`CodError`] is returned and nothing is changed.
+    pub fn move_node<T: NodeClone + Clone, P: NodeClone + Clone>(
+        &mut self,
+        id: ID,
+        new_parent_id: ID,
+        insert: impl FnOnce(&mut P, Child<T>),
+    ) -> Result<(), EditError> {
+        self.sync();
+        self.check_type::<T>(id)?;
+        self.check_type::<P>(new_parent_id)?;
+        let mut ancestor = Some(new_parent_id);
+        while let Some(ancestor_id) = ancestor {
+            if ancestor_id == id {
+                return Err(CodError::MoveIntoSubtree(id).into())
+            }
+            ancestor = self.parent_of(ancestor_id);
+        }
+        // not noticed by the session, so the subtree keeps its IDs
+        let node = self.take_from_parent(id)?;
+        self.edit(id).header_mut().set_parent_id(Some(new_parent_id));
+        // the previous version, replaced by the working copy when committing
+        let child = Child::from_inner(downcast_rc(node).unwrap());
+        insert(downcast_mut(self.edit(new_parent_id)).unwrap(), child);
+        Ok(())
+    }
+
+    /// Add `node` as a child of the node with ID `parent_id`, and return the ID of `node`.
+    /// `insert` puts the `Child`, which already has the right parent ID, into the parent.
+    ///
+    /// `node` may have been built outside of any mutation session, its subtree is
+    /// added to the state either way. The subtree keeps its IDs, so if one of them is
+    /// already in use, such as with a clone of a node in the state,
+    /// [`CodError::DuplicateID`] is returned and nothing is changed.
+    pub fn insert_child<P: NodeClone + Clone, T: NodeClone + Clone>(
+        &mut self,
+        parent_id: ID,
+        node: T,
+        insert: impl FnOnce(&mut P, Child<T>),
+    ) -> Result<ID, EditError> {
+        self.sync();
+        self.check_type::<P>(parent_id)?;
+        let node = Rc::new(node);
+        let id = node.header().id;
+        let mut ids = HashSet::new();
+        let duplicate = Walk::from_node(Rc::clone(&node) as Rc<dyn NodeClone>)
+            .map(|descendant| descendant.header().id)
+            .find(|&id| self.id_lookup.contains_key(&id) || !ids.insert(id));
+        if let Some(duplicate) = duplicate {
+            // may share its subtree with the state, which must not be removed
+            with_context(|c| Context::suspend(c, || drop(node)));
+            return Err(CodError::DuplicateID(duplicate).into())
+        }
+        // copies the subtree, keeping IDs but registering all of it
+        let linked = with_context(|c| {
+            let linked = Context::poll_mut(c, PollReason::Relink(Some(parent_id)), Rc::clone(&node)).unwrap();
+            // the original is not removed from the state
+            Context::suspend(c, || drop(node));
+            linked
+        });
+        insert(downcast_mut(self.edit(parent_id)).unwrap(), Child::from_inner(linked));
+        Ok(id)
+    }
+
+    /// Remove the node with the given ID along with its subtree, without needing to
+    /// know the fields of its parent.
+    ///
+    /// The node is taken out of its parent with [`Node::take_child`](crate::Node::take_child).
+    /// If that is not possible, [`CodError::CannotRemove`] is returned and nothing is changed.
+    /// Nodes referring to the subtree are handled when committing, see [`RefPolicy`](crate::RefPolicy).
+    pub fn remove(&mut self, id: ID) -> Result<(), EditError> {
+        self.sync();
+        self.current(id)?;
+        let node = self.take_from_parent(id)?;
+        // noticed by the session like a dropped `Child`
+        with_context(|c| Context::poll_dyn(c, PollReason::Drop, node));
+        Ok(())
+    }
+
+    /// Take the `Child` with the given ID out of the working copy of its parent, without
+    /// this being noticed by the session. Fails if the parent cannot do without it,
+    /// or if the parent ID of the node is wrong.
+    fn take_from_parent(&mut self, id: ID) -> Result<Rc<dyn NodeClone>, CodError> {
+        let parent_id = self.parent_of(id).ok_or(CodError::CannotRemove(id))?;
+        self.current(parent_id).map_err(|_| CodError::ChildNotFound { parent: parent_id })?;
+        let parent = self.edit(parent_id);
+        with_context(|c| Context::suspend(c, || parent.take_child(id))).ok_or(CodError::CannotRemove(id))
+    }
+
+    /// Catch up with the changes made in the mutation session.
+    fn sync(&mut self) {
+        self.session.activate();
+        let updates: Vec<_> = self.session.take_updates().collect();
+        self.check_updates(&updates);
+        apply_updates(&mut self.id_lookup, &mut self.indexes, updates.into_iter());
+    }
+
+    /// Fail the transaction if a node with a working copy got another new version
+    /// in the mutation session, through `Child::make_mut` on its parent.
+    fn check_updates(&mut self, updates: &[IDMapUpdate]) {
+        for id in new_versions(updates) {
+            if self.edited.contains_key(&id) {
+                self.error.get_or_insert(CodError::EditedTwice(id));
+            }
+            self.new_versions.push(id);
+        }
+    }
+
+    /// The current version of the node with the given ID.
+    fn current(&self, id: ID) -> Result<Rc<dyn NodeClone>, LookupError> {
+        match self.edited.get(&id) {
+            Some(node) => Ok(Rc::clone(node)),
+            None => self.id_lookup.get(&id).and_then(Weak::upgrade).ok_or(LookupError::NotFound(id)),
+        }
+    }
+
+    fn check_type<T: NodeClone>(&self, id: ID) -> Result<(), LookupError> {
+        let node = self.current(id)?;
+        if Any::type_id(&*node) == TypeId::of::<T>() {
+            Ok(())
+        } else {
+            Err(LookupError::WrongType {
+                id,
+                expected: std::any::type_name::<T>(),
+                found: node.type_name(),
+            })
+        }
+    }
+
+    /// The parent ID of the node with the given ID. Children added to the working
+    /// copies with [`Child::new`] have no parent ID until they are adopted here.
+    fn parent_of(&mut self, id: ID) -> Option<ID> {
+        let node = self.current(id).ok()?;
+        if node.header().parent_id().is_none() && id != self.state.root.header().id {
+            let _idle = Session::idle();
+            if let Err(error) = adopt_edited_children(&self.id_lookup, &self.edited) {
+                self.error.get_or_insert(error);
+            }
+        }
+        node.header().parent_id()
+    }
+
+    /// The working copy of the node with the given ID, which must exist.
+    /// It is made the first time the node is accessed during the transaction.
+    fn edit(&mut self, id: ID) -> &mut dyn NodeClone {
+        if !self.edited.contains_key(&id) {
+            let node = self.id_lookup.get(&id).and_then(Weak::upgrade).unwrap();
+            // the children are shared, not deep copied
+            let copy = with_context(|c| Context::suspend(c, || node.dyn_clone()));
+            self.edited.insert(id, copy);
+        }
+        Rc::get_mut(self.edited.get_mut(&id).unwrap()).unwrap()
+    }
+
+    /// Mutate the node with the given ID in a closure.
+    pub fn update<T: NodeClone + Clone, O>(&mut self, id: ID, update: impl FnOnce(&mut T) -> O)
+        -> Result<O, LookupError> {
+        Ok(update(self.try_get_mut(id)?))
+    }
+
+    fn commit(mut self) -> Result<(), CodError> {
+        let updates: Vec<_> = self.session.end()?.collect();
+        self.check_updates(&updates);
+        if let Some(error) = self.error {
+            return Err(error)
+        }
+        let mut id_lookup = std::mem::take(&mut self.id_lookup);
+        let mut indexes = std::mem::take(&mut self.indexes);
+        apply_updates(&mut id_lookup, &mut indexes, updates.into_iter());
+        let new_versions = std::mem::take(&mut self.new_versions);
+        self.state.propagate(id_lookup, indexes, std::mem::take(&mut self.edited), new_versions)
+    }
+}
+
+impl<'a, R: NodeClone + Clone> Drop for Transaction<'a, R> {
+    fn drop(&mut self) {
+        // the working copies, if not committed, are dropped without being noticed
+        self.session.abort();
+    }
+}
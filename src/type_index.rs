@@ -0,0 +1,70 @@
+//! Finding all nodes of a type, optionally backed by a secondary index.
+
+use std::any::{Any, TypeId};
+use crate::{NodeClone, State, ID, Rc, Weak, im};
+use crate::danger_zone::downcast_rc;
+
+/// IDs of the nodes in a state, grouped by node type. Kept in sync with the
+/// ID lookup by [`apply_updates`](crate::apply_updates).
+#[derive(Clone, Default)]
+pub(crate) struct TypeIndex {
+    by_type: im::HashMap<TypeId, im::OrdSet<ID>>,
+}
+
+impl TypeIndex {
+    pub(crate) fn insert(&mut self, id: ID, type_id: TypeId) {
+        self.by_type.entry(type_id).or_default().insert(id);
+    }
+
+    /// There are few node types, so looking through all of them is cheaper
+    /// than remembering the type of every node.
+    pub(crate) fn remove(&mut self, id: ID) {
+        for (_, ids) in self.by_type.iter_mut() {
+            if ids.remove(&id).is_some() {
+                return
+            }
+        }
+    }
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// Build an index of the nodes by type, so that [`iter_of_type`](State::iter_of_type)
+    /// and [`count_of_type`](State::count_of_type) do not need to look through all nodes.
+    /// The index is kept up to date in the states derived from this one.
+    pub fn with_type_index(mut self) -> Self {
+        let mut index = TypeIndex::default();
+        for (&id, node) in &self.id_lookup {
+            if let Some(node) = Weak::upgrade(node) {
+                index.insert(id, Any::type_id(&*node));
+            }
+        }
+        self.indexes.types = Some(index);
+        self
+    }
+
+    /// Whether this state has an index of the nodes by type, see [`with_type_index`](State::with_type_index).
+    pub fn has_type_index(&self) -> bool {
+        self.indexes.types.is_some()
+    }
+
+    /// All nodes of type `T`. Ordered by ID if the state has a type index,
+    /// otherwise in no particular order.
+    pub fn iter_of_type<T: NodeClone>(&self) -> impl Iterator<Item=Rc<T>> + '_ {
+        let nodes: Box<dyn Iterator<Item=&Weak<dyn NodeClone>>> = match &self.indexes.types {
+            Some(index) => match index.by_type.get(&TypeId::of::<T>()) {
+                Some(ids) => Box::new(ids.iter().filter_map(move |id| self.id_lookup.get(id))),
+                None => Box::new(std::iter::empty()),
+            },
+            None => Box::new(self.id_lookup.values()),
+        };
+        nodes.filter_map(|node| downcast_rc(Weak::upgrade(node)?))
+    }
+
+    /// Number of nodes of type `T`.
+    pub fn count_of_type<T: NodeClone>(&self) -> usize {
+        match &self.indexes.types {
+            Some(index) => index.by_type.get(&TypeId::of::<T>()).map_or(0, |ids| ids.len()),
+            None => self.iter_of_type::<T>().count(),
+        }
+    }
+}
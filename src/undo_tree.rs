@@ -0,0 +1,236 @@
+//! Branching undo history, similar to the undo tree in vim.
+//!
+//! Undoing and then making a new change starts a new branch instead of
+//! discarding the undone changes.
+
+use std::time::SystemTime;
+use crate::{NodeClone, State, MutRef, Rc};
+
+/// Identifies a checkpoint within an [`UndoTree`].
+pub type CheckpointID = usize;
+
+/// One recorded version in an [`UndoTree`].
+pub struct Checkpoint<R: NodeClone + Clone> {
+    state: State<R>,
+    parent: Option<CheckpointID>,
+    children: Vec<CheckpointID>,
+    /// Child to go to on redo, the most recently visited branch.
+    redo_child: Option<CheckpointID>,
+    label: Option<String>,
+    timestamp: SystemTime,
+}
+
+impl<R: NodeClone + Clone> Checkpoint<R> {
+    pub fn state(&self) -> &State<R> {
+        &self.state
+    }
+
+    /// The checkpoint this one was derived from. `None` for the initial checkpoint.
+    pub fn parent(&self) -> Option<CheckpointID> {
+        self.parent
+    }
+
+    /// Checkpoints derived from this one, i.e. the branches, in creation order.
+    pub fn children(&self) -> &[CheckpointID] {
+        &self.children
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// When the checkpoint was created.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+}
+
+/// Keeps every recorded version of a [`State`] in a tree.
+///
+/// Each mutation session started through the tree that changes the state creates
+/// a new checkpoint as a child of the current one, and makes it current.
+pub struct UndoTree<R: NodeClone + Clone> {
+    checkpoints: Vec<Checkpoint<R>>,
+    current: CheckpointID,
+    /// The state as edited through the tree, ahead of the current checkpoint while
+    /// an edit is pending.
+    state: State<R>,
+    /// Label and time of an edit that is recorded once it is known to have changed the state.
+    pending: Option<(Option<String>, SystemTime)>,
+}
+
+impl<R: NodeClone + Clone> UndoTree<R> {
+    /// Start a tree with `state` as the initial checkpoint.
+    pub fn new(state: State<R>) -> Self {
+        UndoTree {
+            checkpoints: vec![Checkpoint {
+                state: state.clone(),
+                parent: None,
+                children: Vec::new(),
+                redo_child: None,
+                label: None,
+                timestamp: SystemTime::now(),
+            }],
+            current: 0,
+            state,
+            pending: None,
+        }
+    }
+
+    pub fn state(&self) -> &State<R> {
+        &self.state
+    }
+
+    pub fn root(&self) -> &R {
+        self.state().root()
+    }
+
+    pub fn root_ref(&self) -> Rc<R> {
+        self.state().root_ref()
+    }
+
+    /// The current checkpoint. A pending change is counted as the checkpoint it
+    /// will be recorded as.
+    pub fn current(&self) -> CheckpointID {
+        if self.has_pending_change() { self.checkpoints.len() } else { self.current }
+    }
+
+    /// The initial checkpoint, which all others descend from.
+    pub fn initial(&self) -> CheckpointID {
+        0
+    }
+
+    /// A recorded checkpoint. A change made through [`get_mut`](UndoTree::get_mut) is
+    /// recorded by the next call that takes `&mut self`.
+    pub fn checkpoint(&self, id: CheckpointID) -> Option<&Checkpoint<R>> {
+        self.checkpoints.get(id)
+    }
+
+    /// All recorded checkpoints in creation order, along with their IDs.
+    pub fn iter(&self) -> impl Iterator<Item=(CheckpointID, &Checkpoint<R>)> {
+        self.checkpoints.iter().enumerate()
+    }
+
+    /// Like [`State::get_mut`], but the mutation is recorded as a new unlabeled checkpoint,
+    /// unless it leaves the state unchanged.
+    pub fn get_mut<T: NodeClone + Clone>(&mut self, node: Rc<T>) -> MutRef<'_, R, T> {
+        self.settle();
+        self.pending = Some((None, SystemTime::now()));
+        self.state.get_mut(node)
+    }
+
+    /// Perform arbitrary modifications on the current state, recording the
+    /// result as a new checkpoint with the given label if the state changed.
+    pub fn edit<O>(&mut self, label: impl Into<String>, edit: impl FnOnce(&mut State<R>) -> O) -> O {
+        self.settle();
+        self.pending = Some((Some(label.into()), SystemTime::now()));
+        let result = edit(&mut self.state);
+        self.settle();
+        result
+    }
+
+    /// Record `state` as a new checkpoint derived from the current one, and make it current.
+    pub fn commit(&mut self, state: State<R>, label: Option<String>) -> CheckpointID {
+        self.settle();
+        self.state = state.clone();
+        self.push(state, label, SystemTime::now())
+    }
+
+    /// Change the label of a checkpoint. Returns false if `id` does not exist.
+    pub fn set_label(&mut self, id: CheckpointID, label: Option<String>) -> bool {
+        self.settle();
+        match self.checkpoints.get_mut(id) {
+            Some(checkpoint) => {
+                checkpoint.label = label;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.has_pending_change() || self.checkpoints[self.current].parent.is_some()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        // a pending change becomes a checkpoint without children
+        !self.has_pending_change() && self.checkpoints[self.current].redo_child.is_some()
+    }
+
+    /// Move to the parent checkpoint. Returns false if already at the initial checkpoint.
+    pub fn undo(&mut self) -> bool {
+        self.settle();
+        match self.checkpoints[self.current].parent {
+            Some(parent) => {
+                self.move_to(parent);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Move to the most recently visited child checkpoint. Returns false if there are none.
+    pub fn redo(&mut self) -> bool {
+        self.settle();
+        match self.checkpoints[self.current].redo_child {
+            Some(child) => {
+                self.move_to(child);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Make any recorded checkpoint current. Returns false if `id` does not exist.
+    ///
+    /// Redo will afterwards follow the path that was taken to `id`.
+    pub fn jump_to(&mut self, id: CheckpointID) -> bool {
+        self.settle();
+        if id >= self.checkpoints.len() { return false }
+        let mut child = id;
+        while let Some(parent) = self.checkpoints[child].parent {
+            self.checkpoints[parent].redo_child = Some(child);
+            child = parent;
+        }
+        self.move_to(id);
+        true
+    }
+
+    fn move_to(&mut self, id: CheckpointID) {
+        self.current = id;
+        self.state = self.checkpoints[id].state.clone();
+    }
+
+    /// Record the pending edit as a checkpoint if the state has changed since the current one.
+    fn settle(&mut self) {
+        let Some((label, timestamp)) = self.pending.take() else { return };
+        if self.has_changed() {
+            self.push(self.state.clone(), label, timestamp);
+        }
+    }
+
+    fn has_pending_change(&self) -> bool {
+        self.pending.is_some() && self.has_changed()
+    }
+
+    fn has_changed(&self) -> bool {
+        !Rc::ptr_eq(&self.state.root, &self.checkpoints[self.current].state.root)
+    }
+
+    fn push(&mut self, state: State<R>, label: Option<String>, timestamp: SystemTime) -> CheckpointID {
+        let id = self.checkpoints.len();
+        self.checkpoints.push(Checkpoint {
+            state,
+            parent: Some(self.current),
+            children: Vec::new(),
+            redo_child: None,
+            label,
+            timestamp,
+        });
+        let parent = &mut self.checkpoints[self.current];
+        parent.children.push(id);
+        parent.redo_child = Some(id);
+        self.current = id;
+        id
+    }
+}
@@ -0,0 +1,65 @@
+//! Iterating over all the nodes of a tree.
+
+use std::collections::VecDeque;
+use crate::{NodeClone, State, ID, Rc};
+use crate::context::{with_context, Context};
+
+/// Iterator over a tree of nodes, see [`State::walk`].
+///
+/// Depth-first by default, visiting each node before its children,
+/// and children in order.
+pub struct Walk {
+    pending: VecDeque<Rc<dyn NodeClone>>,
+    breadth_first: bool,
+}
+
+impl Walk {
+    /// Iterate over the subtree of `node`, which need not be part of a state.
+    pub(crate) fn from_node(node: Rc<dyn NodeClone>) -> Self {
+        Walk {
+            pending: VecDeque::from([node]),
+            breadth_first: false,
+        }
+    }
+
+    /// Visit the nodes breadth-first instead, level by level.
+    pub fn breadth_first(mut self) -> Self {
+        self.breadth_first = true;
+        self
+    }
+}
+
+impl Iterator for Walk {
+    type Item = Rc<dyn NodeClone>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = if self.breadth_first {
+            self.pending.pop_front()?
+        } else {
+            self.pending.pop_back()?
+        };
+        let children = with_context(|c| Context::children(c, &*node));
+        if self.breadth_first {
+            self.pending.extend(children);
+        } else {
+            self.pending.extend(children.into_iter().rev());
+        }
+        Some(node)
+    }
+}
+
+impl<R: NodeClone + Clone> State<R> {
+    /// Iterate over all the nodes in the state, starting from the root.
+    pub fn walk(&self) -> Walk {
+        Walk::from_node(Rc::clone(&self.root) as Rc<dyn NodeClone>)
+    }
+
+    /// Iterate over the subtree of the node with the given ID, starting from the node.
+    /// Empty if there is no such node.
+    pub fn walk_subtree(&self, id: ID) -> Walk {
+        Walk {
+            pending: self.ref_from_id(id).into_iter().collect(),
+            breadth_first: false,
+        }
+    }
+}